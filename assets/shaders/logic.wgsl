@@ -1,3 +1,5 @@
+#import "shaders/neighbours.wgsl"::{search_rings, ring_cell_count, ring_cell}
+
 struct Unit {
     previous_state : vec2<f32>,
     current_state : vec2<f32>,
@@ -6,6 +8,73 @@ struct Unit {
     attack_id : i32,
     id : i32,
     health : i32,
+    faction : i32,
+    unit_type : i32,
+    reload : i32,
+    morale : f32,
+    routing : i32,
+}
+
+struct Projectile {
+    previous_position : vec2<f32>,
+    position : vec2<f32>,
+    velocity : vec2<f32>,
+    impact : vec2<f32>,
+    damage : i32,
+    faction : i32,
+    splash_radius : f32,
+    ticks_left : i32,
+}
+
+//accumulated while a tick runs and resolved by apply_damage, so attackers never write to other units directly
+struct UnitCombat {
+    damage : atomic<i32>,
+    attacker : atomic<i32>,
+    //bit per faction that damaged this unit this tick
+    hits : atomic<u32>,
+}
+
+struct FactionCounts {
+    alive : atomic<u32>,
+    kills : atomic<u32>,
+}
+
+//indirect dispatch arguments for the per unit passes and the sort, followed by the live unit count
+struct LiveUnits {
+    dispatch_x : u32,
+    dispatch_y : u32,
+    dispatch_z : u32,
+    count : u32,
+    sort_x : u32,
+    sort_y : u32,
+    sort_z : u32,
+    //unit count after the running compaction, copied into count by compact_finish
+    next_count : u32,
+}
+
+//units start..end of the sorted unit buffer are in the cell, start == end for empty cells
+struct CellRange {
+    start : u32,
+    end : u32,
+}
+
+struct Faction {
+    color : vec4<f32>,
+    advance : vec2<f32>,
+}
+
+struct UnitTypeStats {
+    max_health : i32,
+    melee_damage : i32,
+    speed : f32,
+    attack_range : f32,
+    protected_range : f32,
+    mass : f32,
+    fire_range : f32,
+    projectile_speed : f32,
+    projectile_damage : i32,
+    reload_ticks : i32,
+    splash_radius : f32,
 }
 
 struct UniformData{
@@ -19,72 +88,189 @@ struct UniformData{
     camera_zoom : f32,
     camera_position : vec2<f32>,
     alpha : f32,
+    targeting_factor : f32,
+    avoid_factor : f32,
+    kill_range : f32,
+    war_zone : f32,
+    faction_count : i32,
+    morale_recovery : f32,
+    outnumbered_penalty : f32,
+    ally_death_penalty : f32,
+    wound_penalty : f32,
+    rout_threshold : f32,
+    rally_threshold : f32,
+    color_mode : i32,
+    boundary_mode : i32,
 }
 
 @group(0) @binding(0)
 var<storage, read_write> units: array<Unit>;
 @group(0) @binding(1)
-var<storage, read_write> indices : array<i32>;
+var<storage, read_write> cells : array<CellRange>;
 @group(0) @binding(2)
 var<uniform> uniform_data : UniformData;
+@group(0) @binding(3)
+var<storage, read> factions : array<Faction>;
+//faction_count * faction_count matrix, 1 where the row faction attacks the column faction
+@group(0) @binding(4)
+var<storage, read> hostility : array<u32>;
+@group(0) @binding(5)
+var<storage, read> unit_types : array<UnitTypeStats>;
+//ring buffer, new projectiles overwrite the oldest slot
+@group(0) @binding(6)
+var<storage, read_write> projectiles : array<Projectile>;
+@group(0) @binding(7)
+var<storage, read_write> projectile_counter : atomic<u32>;
+//one entry per unit slot, indexed like units
+@group(0) @binding(8)
+var<storage, read_write> combat : array<UnitCombat>;
+
+//attack_id of a unit that died this tick, it stays in its cell for one more tick so its neighbours notice
+const FALLEN = -2;
+//hash id of the padding units behind the live ones, see unit.rs
+const SENTINEL_HASH = 2147483647;
+
+@group(0) @binding(9)
+var<storage, read_write> live : LiveUnits;
+//compaction target, copied back over units once it is filled
+@group(0) @binding(10)
+var<storage, read_write> compacted : array<Unit>;
+//living units per block of workgroup_s units, turned into block offsets by compact_scan
+@group(0) @binding(11)
+var<storage, read_write> block_sums : array<u32>;
+
+//unit count per bucket, turned into bucket offsets and then bucket ends by the counting sort
+//bucket 0 holds dead units and bucket c + 1 holds cell c, the last one being the overflow cell
+@group(0) @binding(12)
+var<storage, read_write> buckets : array<atomic<u32>>;
+//living units per faction, recounted by count_factions, and kills per faction over the whole battle
+@group(0) @binding(13)
+var<storage, read_write> faction_counts : array<FactionCounts>;
+
+var<workgroup> scan_scratch : array<u32, workgroup_s>;
+var<workgroup> faction_scratch : array<atomic<u32>, MAX_FACTIONS>;
 
-const targeting_factor : f32 = 0.5;
-const avoid_factor : f32 = 2.0;
-const protected_range : f32 = 4.0;
-const max_speed = 0.5;
-const workgroup_s = 256;
-const war_zone : f32 = 5.0;
-const attack_range : f32 = 20.0;
-const kill_range : f32 = 4.0;
+const workgroup_s = #{WORKGROUP_SIZE};
+//same as MAX_FACTIONS in config.rs
+const MAX_FACTIONS = 16u;
 
-const offsets = array(
-    vec2<i32>(-1, 1), vec2<i32>(0, 1), vec2<i32>(1, 1),
-    vec2<i32>(-1, 0), vec2<i32>(0, 0), vec2<i32>(1, 0),
-    vec2<i32>(-1,-1), vec2<i32>(0,-1), vec2<i32>(1,-1),
-);
+//same order as BoundaryMode in config.rs
+const BOUNDARY_CLAMP = 0;
+const BOUNDARY_REFLECT = 1;
+const BOUNDARY_WRAP = 2;
+const BOUNDARY_KILL = 3;
 
-fn dimensionalize(offset : vec2<i32>) -> i32{
-    return offset.x - offset.y*uniform_data.grid_width;
+//the grid spans the world, centred on the origin
+fn world_extent() -> vec2<f32> {
+    return vec2<f32>(f32(uniform_data.grid_width), f32(uniform_data.grid_height)) * f32(uniform_data.grid_size);
 }
+
+//the cell after the last grid cell collects every unit that is outside the grid
+fn overflow_cell() -> i32 {
+    return uniform_data.grid_width * uniform_data.grid_height;
+}
+
+fn cell_coords(position : vec2<f32>) -> vec2<i32> {
+    let grid = vec2<i32>(uniform_data.grid_width, uniform_data.grid_height);
+    let cell = vec2<i32>(floor(position / f32(uniform_data.grid_size) + vec2<f32>(grid) / 2.0));
+    //the far edges are still inside the world, but floor to one past the last cell
+    if (all(abs(position) <= world_extent() / 2.0)) {
+        return min(cell, grid - 1);
+    }
+    return cell;
+}
+
 fn compute_hash_id(position : vec2<f32>) -> i32{
-    let x = i32((position.x / f32(uniform_data.grid_size)) 
-        + (f32(uniform_data.grid_width)/2.0));
-    let y = i32((position.y / f32(uniform_data.grid_size)) 
-        + (f32(uniform_data.grid_height)/2.0));
-    return x + (y*uniform_data.grid_width);
+    let cell = cell_coords(position);
+    if (cell.x < 0 || cell.y < 0 || cell.x >= uniform_data.grid_width || cell.y >= uniform_data.grid_height) {
+        return overflow_cell();
+    }
+    return cell.x + (cell.y*uniform_data.grid_width);
+}
+
+fn wrap_position(position : vec2<f32>) -> vec2<f32> {
+    let size = world_extent();
+    return position - size * floor((position + size / 2.0) / size);
+}
+
+//shortest vector from one position to another, across the edges when the world wraps
+fn displacement(origin : vec2<f32>, target_position : vec2<f32>) -> vec2<f32> {
+    var offset = target_position - origin;
+    if (uniform_data.boundary_mode == BOUNDARY_WRAP) {
+        let size = world_extent();
+        offset -= size * round(offset / size);
+    }
+    return offset;
 }
 
 @compute @workgroup_size(workgroup_s, 1, 1)
 fn hash(@builtin(global_invocation_id) invocation_id: vec3<u32>){
     let index = i32(invocation_id.x);
+    if (index >= i32(live.count)) {
+        return;
+    }
     if(units[index].health <= 0) {
+        if (units[index].attack_id == FALLEN) {
+            units[index].attack_id = -1;
+        }
+        else if (units[index].hash_id >= 0) {
+            units[index].hash_id = -999;
+        }
         return;
     } 
+    //neighbours only read this snapshot during update, so the order units move in does not matter
+    units[index].previous_state = units[index].current_state;
     units[index].hash_id = compute_hash_id(units[index].current_state);
 }
 
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn clear_cells(@builtin(global_invocation_id) invocation_id: vec3<u32>){
+    if (invocation_id.x < arrayLength(&cells)) {
+        cells[invocation_id.x] = CellRange(0u, 0u);
+    }
+}
+
+//runs on the sorted units, the first and last unit of every run of equal hash ids mark the cell bounds
 @compute @workgroup_size(workgroup_s, 1, 1)
 fn hash_indices(@builtin(global_invocation_id) invocation_id: vec3<u32>){
-    
-    var prev_key : i32 = 0;
-    let index = i32(invocation_id.x);
+    let index = invocation_id.x;
+    if (index >= live.count) {
+        return;
+    }
     let key = units[index].hash_id;
-    if (index == 0){
-        prev_key = -1;
+    if (key < 0 || key >= i32(arrayLength(&cells))) {
+        return;
     }
-    else {
-        prev_key = units[index - 1].hash_id;
+    if (index == 0u || units[index - 1u].hash_id != key){
+        cells[key].start = index;
+    }
+    if (index + 1u == live.count || units[index + 1u].hash_id != key){
+        cells[key].end = index + 1u;
+    }
+}
+
+//empty for cells outside the grid, which only exist when the world does not wrap
+fn cell_range(cell_in : vec2<i32>) -> CellRange {
+    var cell = cell_in;
+    if (uniform_data.boundary_mode == BOUNDARY_WRAP) {
+        let size = vec2<i32>(uniform_data.grid_width, uniform_data.grid_height);
+        cell = ((cell % size) + size) % size;
+    }
+    if (cell.x < 0 || cell.y < 0 || cell.x >= uniform_data.grid_width || cell.y >= uniform_data.grid_height) {
+        return CellRange(0u, 0u);
     }
-    if (prev_key != key){
-        indices[key] = index;
-    }  
+    return cells[cell.x + cell.y * uniform_data.grid_width];
 }
 
 @compute @workgroup_size(workgroup_s, 1, 1)
 fn sort(@builtin(global_invocation_id) invocation_id: vec3<u32>){
     let idx_start = i32(invocation_id.x);
     let half_step = uniform_data.step/2;
-    let low = (idx_start/half_step) * uniform_data.step + (idx_start % half_step);          
+    let low = (idx_start/half_step) * uniform_data.step + (idx_start % half_step);
+    //the sort runs over the whole padded buffer, sentinel units sort to the back
+    if (u32(low + half_step) >= arrayLength(&units)) {
+        return;
+    }
     let direction = ((low/uniform_data.level) + 1)%2;
     compare(
         u32(low),
@@ -104,57 +290,76 @@ fn compare(a: u32, b: u32, direction: i32) {
     }
 }
 
-fn get_side(id : i32) -> i32{
-    if (id >= uniform_data.unit_count/2){
-        return 1;
-    }
-    return 0;
+fn is_hostile(faction : i32, other : i32) -> bool{
+    return hostility[faction * uniform_data.faction_count + other] != 0u;
 }
 
 @compute @workgroup_size(workgroup_s, 1, 1)
 fn update(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let index = i32(invocation_id.x); 
+    let index = i32(invocation_id.x);
+    if (index >= i32(live.count)) {
+        return;
+    }
     if(units[index].health <= 0){
-        if (units[index].hash_id >= 0) {
-            units[index].hash_id = -999;
-        }
         return;
     }
     var current_state : vec2<f32> = units[index].current_state;
-    units[index].previous_state = current_state;
     var velocity : vec2<f32> = units[index].velocity;
     let hash_id = units[index].hash_id;
     let id = units[index].id;
-    let side = get_side(id);
+    let faction = units[index].faction;
+    let stats = unit_types[units[index].unit_type];
     var closest : f32 = 1000.0;
     let attack_id = units[index].attack_id;
     var new_attack_id : i32 = -1;
     var enemy_index : i32 = -1;
+    var allies : i32 = 0;
+    var enemies : i32 = 0;
+    var fallen_allies : i32 = 0;
+    var flee : vec2<f32> = vec2<f32>(0.0, 0.0);
 
-    for(var j = 0;j<9;j++){
-        
-        let new_hash_id = hash_id+dimensionalize(offsets[j]);
-
-        let start_index = indices[new_hash_id];
-        for(var i = i32(start_index); i < uniform_data.unit_count; i++) {
-            
-            if(new_hash_id != units[i].hash_id){
-                break;
-            }
+    //enough rings to see every unit within attack or avoidance range of this unit type
+    let rings = search_rings(max(stats.attack_range, stats.protected_range), uniform_data.grid_size);
+    //units in the overflow cell have no neighbours until the boundary brings them back
+    var scan = ring_cell_count(rings);
+    if (hash_id == overflow_cell()) {
+        scan = 0;
+    }
+    let cell = vec2<i32>(hash_id % uniform_data.grid_width, hash_id / uniform_data.grid_width);
+    for(var j = 0;j<scan;j++){
+        let range = cell_range(ring_cell(cell, rings, j));
+        for(var i = i32(range.start); i < i32(range.end); i++) {
             if (i != index){
-                let e_position = units[i].current_state;
+                let e_position = units[i].previous_state;
                 let e_id = units[i].id;
-                let e_side = get_side(units[i].id);
+                let e_faction = units[i].faction;
 
-                let offset = current_state - e_position;
+                let offset = displacement(e_position, current_state);
                 let dist = length(offset);
-                if (dist < protected_range){
+                let hostile = is_hostile(faction, e_faction);
+                //units that died last tick are still in their cell until their own update clears the hash id
+                if (units[i].health <= 0) {
+                    if (!hostile) {
+                        fallen_allies++;
+                    }
+                    continue;
+                }
+                if (hostile) {
+                    enemies++;
+                    flee += offset / max(dist * dist, 0.0001);
+                }
+                else {
+                    allies++;
+                }
+                if (dist < stats.protected_range){
+                    //heavier neighbours push harder, equal masses push with the plain avoid factor
+                    let e_mass = unit_types[units[i].unit_type].mass;
                     let norm = normalize(offset);
-                    let avoid = norm * avoid_factor * (protected_range/dist);
+                    let avoid = norm * uniform_data.avoid_factor * (stats.protected_range/dist) * (2.0 * e_mass / (stats.mass + e_mass));
                     velocity += avoid;
                 }
-                if (attack_id == -1 && e_side != side) {
-                    if (dist < closest && dist < attack_range) {
+                if (attack_id == -1 && hostile) {
+                    if (dist < closest && dist < stats.attack_range) {
                         new_attack_id = e_id;
                         enemy_index = i;
                         closest = dist;
@@ -170,29 +375,409 @@ fn update(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         }
     }
 
-    if (new_attack_id != -1) {
-        units[enemy_index].attack_id = id;
-        velocity += normalize(units[enemy_index].current_state-current_state)*targeting_factor;
-        if(length(units[enemy_index].current_state - current_state) < kill_range) {
-            units[enemy_index].health -= 1;
-        }
+    //morale sinks while outnumbered, next to fallen allies and when wounded, and slowly recovers otherwise
+    let wounds = 1.0 - f32(units[index].health) / f32(stats.max_health);
+    var morale = units[index].morale + uniform_data.morale_recovery
+        - f32(max(enemies - allies, 0)) * uniform_data.outnumbered_penalty
+        - f32(fallen_allies) * uniform_data.ally_death_penalty
+        - wounds * uniform_data.wound_penalty;
+    morale = clamp(morale, 0.0, 1.0);
+    var routing = units[index].routing;
+    if (routing == 0 && morale < uniform_data.rout_threshold) {
+        routing = 1;
     }
-    else if (abs(current_state.x) < war_zone || abs(current_state.x) > f32(uniform_data.grid_width * uniform_data.grid_size)* 0.45) {
-        velocity += normalize(vec2<f32>(0.0,0.0)-current_state)*targeting_factor;
+    else if (routing == 1 && morale > uniform_data.rally_threshold) {
+        routing = 0;
     }
-    else if (side == 1) {
-        velocity.x -= targeting_factor;
+
+    var reload = units[index].reload - 1;
+    if (routing == 1) {
+        //routing units drop their target and run from the enemies around them, or keep running once clear
+        new_attack_id = -1;
+        //enemies on top of the unit or on opposite sides cancel out and leave nothing to normalize
+        if (enemies > 0 && length(flee) > 0.0) {
+            velocity += normalize(flee) * uniform_data.targeting_factor;
+        }
     }
-    else if (side == 0) {
-        velocity.x += targeting_factor;
+    else if (new_attack_id != -1) {
+        claim(enemy_index, id);
+        let e_position = units[enemy_index].previous_state;
+        let dist = length(displacement(current_state, e_position));
+        if (dist >= uniform_data.kill_range && dist < stats.fire_range) {
+            //ranged units hold their ground and shoot instead of closing in
+            if (reload <= 0) {
+                fire(current_state, current_state + displacement(current_state, e_position), faction, stats);
+                reload = stats.reload_ticks;
+            }
+            velocity *= 0.5;
+        }
+        else {
+            velocity += normalize(displacement(current_state, e_position))*uniform_data.targeting_factor;
+        }
+        if(dist < uniform_data.kill_range) {
+            atomicAdd(&combat[enemy_index].damage, stats.melee_damage);
+            atomicOr(&combat[enemy_index].hits, 1u << u32(faction));
+        }
+    }
+    else {
+        //march along the faction's advance direction until reaching the front line or the edge of the world
+        let advance = factions[faction].advance;
+        let world_size = vec2<f32>(f32(uniform_data.grid_width), f32(uniform_data.grid_height)) * f32(uniform_data.grid_size);
+        let progress = abs(dot(current_state, advance));
+        if (progress < uniform_data.war_zone || progress > dot(abs(advance), world_size) * 0.45) {
+            velocity += normalize(vec2<f32>(0.0,0.0)-current_state)*uniform_data.targeting_factor;
+        }
+        else {
+            velocity += advance * uniform_data.targeting_factor;
+        }
     }
 
-    velocity = normalize(velocity) * clamp(length(velocity),-max_speed,max_speed);
+    velocity = normalize(velocity) * clamp(length(velocity),-stats.speed,stats.speed);
     
     current_state += velocity;
 
+    let half_world = world_extent() / 2.0;
+    switch (uniform_data.boundary_mode) {
+        case BOUNDARY_REFLECT: {
+            //mirror the overshoot back inside and bounce the velocity off the edge
+            let overshoot = abs(current_state) - half_world;
+            if (overshoot.x > 0.0) {
+                current_state.x = sign(current_state.x) * (half_world.x - overshoot.x);
+                velocity.x = -velocity.x;
+            }
+            if (overshoot.y > 0.0) {
+                current_state.y = sign(current_state.y) * (half_world.y - overshoot.y);
+                velocity.y = -velocity.y;
+            }
+            current_state = clamp(current_state, -half_world, half_world);
+        }
+        case BOUNDARY_WRAP: {
+            current_state = wrap_position(current_state);
+        }
+        case BOUNDARY_KILL: {
+            //resolved by apply_damage like any other death, so neighbours see a consistent unit this tick
+            if (any(abs(current_state) > half_world)) {
+                atomicAdd(&combat[index].damage, units[index].health);
+            }
+        }
+        default: {
+            current_state = clamp(current_state, -half_world, half_world);
+        }
+    }
+
     units[index].attack_id = new_attack_id;
     units[index].current_state = current_state;
     units[index].velocity = velocity;
-    
-}
\ No newline at end of file
+    units[index].reload = max(reload, 0);
+    units[index].morale = morale;
+    units[index].routing = routing;
+}
+
+//keeps the lowest attacker id, which makes the winner independent of the order threads run in
+fn claim(slot : i32, id : i32) {
+    var current = atomicLoad(&combat[slot].attacker);
+    loop {
+        if (current != -1 && current <= id) {
+            break;
+        }
+        let result = atomicCompareExchangeWeak(&combat[slot].attacker, current, id);
+        if (result.exchanged) {
+            break;
+        }
+        current = result.old_value;
+    }
+}
+
+fn fire(position : vec2<f32>, impact : vec2<f32>, faction : i32, stats : UnitTypeStats) {
+    let slot = atomicAdd(&projectile_counter, 1u) % arrayLength(&projectiles);
+    let ticks = max(i32(ceil(length(impact - position) / stats.projectile_speed)), 1);
+    projectiles[slot] = Projectile(
+        position,
+        position,
+        (impact - position) / f32(ticks),
+        impact,
+        stats.projectile_damage,
+        faction,
+        stats.splash_radius,
+        ticks,
+    );
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn update_projectiles(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= arrayLength(&projectiles) || projectiles[index].ticks_left <= 0) {
+        return;
+    }
+    var projectile = projectiles[index];
+    projectile.previous_position = projectile.position;
+    projectile.position += projectile.velocity;
+    projectile.ticks_left -= 1;
+    projectiles[index] = projectile;
+    if (projectile.ticks_left > 0) {
+        return;
+    }
+
+    var impact = projectile.impact;
+    if (uniform_data.boundary_mode == BOUNDARY_WRAP) {
+        impact = wrap_position(impact);
+    }
+    let cell = cell_coords(impact);
+    let rings = search_rings(projectile.splash_radius, uniform_data.grid_size);
+    for(var j = 0;j<ring_cell_count(rings);j++){
+        let range = cell_range(ring_cell(cell, rings, j));
+        for(var i = range.start; i < range.end; i++) {
+            if (units[i].health > 0 && is_hostile(projectile.faction, units[i].faction) 
+                && length(displacement(impact, units[i].current_state)) < projectile.splash_radius) {
+                atomicAdd(&combat[i].damage, projectile.damage);
+                atomicOr(&combat[i].hits, 1u << u32(projectile.faction));
+            }
+        }
+    }
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn apply_damage(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = i32(invocation_id.x);
+    if (index >= i32(live.count)) {
+        return;
+    }
+    let damage = atomicExchange(&combat[index].damage, 0);
+    let attacker = atomicExchange(&combat[index].attacker, -1);
+    let hits = atomicExchange(&combat[index].hits, 0u);
+    if (units[index].health <= 0) {
+        return;
+    }
+    units[index].health -= damage;
+    if (units[index].health <= 0) {
+        units[index].attack_id = FALLEN;
+        //shared kills go to the lowest faction so the totals do not depend on thread order
+        if (hits != 0u) {
+            atomicAdd(&faction_counts[firstTrailingBit(hits)].kills, 1u);
+        }
+        return;
+    }
+    //a unit that is attacked without a target of its own turns on its attacker
+    if (attacker != -1 && units[index].attack_id == -1 && units[index].routing == 0) {
+        units[index].attack_id = attacker;
+    }
+}
+
+//units that are alive, or fell this tick and still have to be seen by their neighbours
+fn is_kept(unit : Unit) -> bool {
+    return unit.health > 0 || unit.attack_id == FALLEN;
+}
+
+//inclusive Hillis-Steele scan over the workgroup, every invocation of the workgroup has to call it
+fn workgroup_scan(local : u32, value : u32) -> u32 {
+    scan_scratch[local] = value;
+    workgroupBarrier();
+    for (var offset = 1u; offset < u32(workgroup_s); offset *= 2u) {
+        var sum = scan_scratch[local];
+        if (local >= offset) {
+            sum += scan_scratch[local - offset];
+        }
+        workgroupBarrier();
+        scan_scratch[local] = sum;
+        workgroupBarrier();
+    }
+    return scan_scratch[local];
+}
+
+fn kept_flag(index : u32) -> u32 {
+    if (index < live.count && is_kept(units[index])) {
+        return 1u;
+    }
+    return 0u;
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn compact_count(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(local_invocation_id) local_id: vec3<u32>, @builtin(workgroup_id) workgroup_id: vec3<u32>) {
+    let total = workgroup_scan(local_id.x, kept_flag(invocation_id.x));
+    if (local_id.x == u32(workgroup_s) - 1u) {
+        block_sums[workgroup_id.x] = total;
+    }
+}
+
+//exclusive scan of the first `blocks` block sums, every invocation of the workgroup has to call it
+//each invocation scans a run of blocks serially, the total is returned to the last invocation
+fn scan_block_sums(local : u32, blocks : u32) -> u32 {
+    let per_invocation = (blocks + u32(workgroup_s) - 1u) / u32(workgroup_s);
+    let first = local * per_invocation;
+    let last = min(first + per_invocation, blocks);
+    var sum = 0u;
+    for (var block = first; block < last; block++) {
+        let count = block_sums[block];
+        block_sums[block] = sum;
+        sum += count;
+    }
+    let inclusive = workgroup_scan(local, sum);
+    for (var block = first; block < last; block++) {
+        block_sums[block] += inclusive - sum;
+    }
+    return inclusive;
+}
+
+//dispatched as a single workgroup
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn compact_scan(@builtin(local_invocation_id) local_id: vec3<u32>) {
+    let blocks = (live.count + u32(workgroup_s) - 1u) / u32(workgroup_s);
+    let total = scan_block_sums(local_id.x, blocks);
+    if (local_id.x == u32(workgroup_s) - 1u) {
+        live.next_count = total;
+    }
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn compact_scatter(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(local_invocation_id) local_id: vec3<u32>, @builtin(workgroup_id) workgroup_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let kept = kept_flag(index);
+    let offset = workgroup_scan(local_id.x, kept);
+    if (kept == 1u) {
+        compacted[block_sums[workgroup_id.x] + offset - 1u] = units[index];
+    }
+    //slots freed by this compaction become padding, slots past the old count already are
+    if (index >= live.next_count && index < live.count) {
+        compacted[index] = Unit(vec2<f32>(0.0), vec2<f32>(0.0), vec2<f32>(0.0), SENTINEL_HASH, -1, -1, 0, 0, 0, 0, 0.0, 0);
+    }
+}
+
+@compute @workgroup_size(1, 1, 1)
+fn compact_finish() {
+    let count = live.next_count;
+    live.count = count;
+    live.dispatch_x = (count + u32(workgroup_s) - 1u) / u32(workgroup_s);
+    //the bitonic network only has to cover the smallest power of two holding every live unit
+    live.sort_x = max(1u << firstLeadingBit(max(count, 1u) * 2u - 1u), 2u * u32(workgroup_s)) / (2u * u32(workgroup_s));
+}
+
+fn bucket(hash_id : i32) -> u32 {
+    if (hash_id < 0) {
+        return 0u;
+    }
+    //anything past the grid already carries the overflow cell's hash id, this only guards against bad input
+    if (hash_id >= i32(arrayLength(&cells))) {
+        return arrayLength(&cells);
+    }
+    return u32(hash_id) + 1u;
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn clear_buckets(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    if (invocation_id.x < arrayLength(&buckets)) {
+        atomicStore(&buckets[invocation_id.x], 0u);
+    }
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn count_buckets(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= live.count) {
+        return;
+    }
+    atomicAdd(&buckets[bucket(units[index].hash_id)], 1u);
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn scan_buckets(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(local_invocation_id) local_id: vec3<u32>, @builtin(workgroup_id) workgroup_id: vec3<u32>) {
+    let index = invocation_id.x;
+    var count = 0u;
+    if (index < arrayLength(&buckets)) {
+        count = atomicLoad(&buckets[index]);
+    }
+    let inclusive = workgroup_scan(local_id.x, count);
+    if (index < arrayLength(&buckets)) {
+        atomicStore(&buckets[index], inclusive - count);
+    }
+    if (local_id.x == u32(workgroup_s) - 1u) {
+        block_sums[workgroup_id.x] = inclusive;
+    }
+}
+
+//dispatched as a single workgroup
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn scan_bucket_blocks(@builtin(local_invocation_id) local_id: vec3<u32>) {
+    scan_block_sums(local_id.x, (arrayLength(&buckets) + u32(workgroup_s) - 1u) / u32(workgroup_s));
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn offset_buckets(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(workgroup_id) workgroup_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= arrayLength(&buckets)) {
+        return;
+    }
+    atomicStore(&buckets[index], atomicLoad(&buckets[index]) + block_sums[workgroup_id.x]);
+}
+
+//leaves every bucket holding the end of its range, which is the start of the next bucket
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn scatter_units(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= live.count) {
+        return;
+    }
+    let slot = atomicAdd(&buckets[bucket(units[index].hash_id)], 1u);
+    compacted[slot] = units[index];
+}
+
+//the scatter fills each bucket in whatever order the threads ran, so every unit counts the lower ids
+//in its bucket and writes itself back to that rank, which keeps ticks deterministic
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn rank_units(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= live.count) {
+        return;
+    }
+    let unit = compacted[index];
+    let slot = bucket(unit.hash_id);
+    //every bucket now points at its end, which is the start of the next one
+    var start = 0u;
+    if (slot > 0u) {
+        start = atomicLoad(&buckets[slot - 1u]);
+    }
+    let end = atomicLoad(&buckets[slot]);
+    var rank = 0u;
+    for (var i = start; i < end; i++) {
+        if (compacted[i].id < unit.id) {
+            rank++;
+        }
+    }
+    units[start + rank] = unit;
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn cell_ranges(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let cell = invocation_id.x;
+    if (cell >= arrayLength(&cells)) {
+        return;
+    }
+    //bucket cell + 1 holds the cell
+    cells[cell] = CellRange(atomicLoad(&buckets[cell]), atomicLoad(&buckets[cell + 1u]));
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn clear_faction_counts(@builtin(local_invocation_index) local: u32) {
+    if (local < arrayLength(&faction_counts)) {
+        atomicStore(&faction_counts[local].alive, 0u);
+    }
+}
+
+//counts into workgroup memory first so every workgroup adds to each faction's total only once
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn count_factions(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(local_invocation_index) local: u32) {
+    if (local < MAX_FACTIONS) {
+        atomicStore(&faction_scratch[local], 0u);
+    }
+    workgroupBarrier();
+    let index = invocation_id.x;
+    if (index < live.count && units[index].health > 0) {
+        atomicAdd(&faction_scratch[units[index].faction], 1u);
+    }
+    workgroupBarrier();
+    if (local < arrayLength(&faction_counts)) {
+        let alive = atomicLoad(&faction_scratch[local]);
+        if (alive != 0u) {
+            atomicAdd(&faction_counts[local].alive, alive);
+        }
+    }
+}
@@ -0,0 +1,24 @@
+//iterates the square block of spatial hash cells around a cell, ring by ring
+//passes own their cell table, so this only yields cell coordinates and leaves the lookup to the caller:
+//
+//    let rings = search_rings(range, uniform_data.grid_size);
+//    for (var j = 0; j < ring_cell_count(rings); j++) {
+//        let cell = ring_cell(center, rings, j);
+//        ...
+//    }
+
+//rings of cells needed so that every point within range of any point in the centre cell is covered
+fn search_rings(range : f32, grid_size : i32) -> i32 {
+    return max(i32(ceil(range / f32(grid_size))), 1);
+}
+
+fn ring_cell_count(rings : i32) -> i32 {
+    let side = 2 * rings + 1;
+    return side * side;
+}
+
+//the j-th cell of the block, row by row starting at the bottom left corner
+fn ring_cell(center : vec2<i32>, rings : i32, j : i32) -> vec2<i32> {
+    let side = 2 * rings + 1;
+    return center + vec2<i32>(j % side - rings, j / side - rings);
+}
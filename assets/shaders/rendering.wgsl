@@ -6,6 +6,38 @@ struct Unit {
     attack_id : i32,
     id : i32,
     health : i32,
+    faction : i32,
+    unit_type : i32,
+    reload : i32,
+    morale : f32,
+    routing : i32,
+}
+
+struct Projectile {
+    previous_position : vec2<f32>,
+    position : vec2<f32>,
+    velocity : vec2<f32>,
+    impact : vec2<f32>,
+    damage : i32,
+    faction : i32,
+    splash_radius : f32,
+    ticks_left : i32,
+}
+
+struct LiveUnits {
+    dispatch_x : u32,
+    dispatch_y : u32,
+    dispatch_z : u32,
+    count : u32,
+    sort_x : u32,
+    sort_y : u32,
+    sort_z : u32,
+    next_count : u32,
+}
+
+struct Faction {
+    color : vec4<f32>,
+    advance : vec2<f32>,
 }
 
 struct UniformData{
@@ -19,6 +51,19 @@ struct UniformData{
     camera_zoom : f32,
     camera_position : vec2<f32>,
     alpha : f32,
+    targeting_factor : f32,
+    avoid_factor : f32,
+    kill_range : f32,
+    war_zone : f32,
+    faction_count : i32,
+    morale_recovery : f32,
+    outnumbered_penalty : f32,
+    ally_death_penalty : f32,
+    wound_penalty : f32,
+    rout_threshold : f32,
+    rally_threshold : f32,
+    color_mode : i32,
+    boundary_mode : i32,
 }
 
 @group(0) @binding(0)
@@ -30,7 +75,16 @@ var texture: texture_storage_2d<rgba8unorm, read_write>;
 @group(0) @binding(2)
 var<uniform> uniform_data : UniformData;
 
-const workgroup_s = 256;
+@group(0) @binding(3)
+var<storage, read> factions : array<Faction>;
+
+@group(0) @binding(4)
+var<storage, read> projectiles : array<Projectile>;
+
+@group(0) @binding(5)
+var<storage, read> live : LiveUnits;
+
+const workgroup_s = #{WORKGROUP_SIZE};
 
 @compute @workgroup_size(32, 32, 1)
 fn clear(@builtin(global_invocation_id) invocation_id: vec3<u32>,@builtin(num_workgroups) num_workgroups: vec3<u32>) {
@@ -42,19 +96,28 @@ fn clear(@builtin(global_invocation_id) invocation_id: vec3<u32>,@builtin(num_wo
 @compute @workgroup_size(workgroup_s, 1, 1)
 fn render(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let index = i32(invocation_id.x);
-    if (units[index].health <= 0){
+    if (index >= i32(live.count) || units[index].health <= 0){
         return;
     }
     let current_state = units[index].current_state;
 
     let previous_state = units[index].previous_state;
-    let pos = current_state * uniform_data.alpha + previous_state * (1.0 - uniform_data.alpha);
+    var pos = current_state * uniform_data.alpha + previous_state * (1.0 - uniform_data.alpha);
+    //units that wrapped around the world this tick would otherwise be drawn across the middle
+    let world_extent = vec2<f32>(f32(uniform_data.grid_width), f32(uniform_data.grid_height)) * f32(uniform_data.grid_size);
+    if (any(abs(current_state - previous_state) > world_extent / 2.0)) {
+        pos = current_state;
+    }
     let screen_position = (pos+uniform_data.camera_position)/uniform_data.camera_zoom + uniform_data.dimensions/2.;
 
     if (screen_position.x > 0.0 && screen_position.x < uniform_data.dimensions.x && screen_position.y > 0.0 && screen_position.y < uniform_data.dimensions.y) {
-            var color : vec4<f32> = vec4f(1.0,0.0,0.0,1.0);
-        if (units[index].id >= uniform_data.unit_count/2) {
-            color = vec4f(0.0,0.0,1.0,1.0);
+        var color = factions[units[index].faction].color;
+        if (uniform_data.color_mode == 1) {
+            //red when about to break, green when steady, white while routing
+            color = mix(vec4<f32>(1.0,0.0,0.0,1.0), vec4<f32>(0.0,1.0,0.0,1.0), units[index].morale);
+            if (units[index].routing == 1) {
+                color = vec4<f32>(1.0,1.0,1.0,1.0);
+            }
         }
 
         let screen_size = clamp(i32(1.0/uniform_data.camera_zoom),1,10);
@@ -66,4 +129,20 @@ fn render(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
             }
         }
     }   
-}
\ No newline at end of file
+}
+
+@compute @workgroup_size(workgroup_s, 1, 1)
+fn render_projectiles(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= arrayLength(&projectiles) || projectiles[index].ticks_left <= 0){
+        return;
+    }
+    let pos = projectiles[index].position * uniform_data.alpha + projectiles[index].previous_position * (1.0 - uniform_data.alpha);
+    let screen_position = (pos+uniform_data.camera_position)/uniform_data.camera_zoom + uniform_data.dimensions/2.;
+
+    if (screen_position.x > 0.0 && screen_position.x < uniform_data.dimensions.x && screen_position.y > 0.0 && screen_position.y < uniform_data.dimensions.y) {
+        //a lighter shade of the faction colour so projectiles stand out from the units
+        let color = mix(factions[projectiles[index].faction].color, vec4<f32>(1.0,1.0,1.0,1.0), 0.6);
+        textureStore(texture, vec2<i32>(screen_position), color);
+    }
+}
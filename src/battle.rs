@@ -0,0 +1,160 @@
+use std::fmt;
+
+use bevy::prelude::*;
+
+use crate::{
+    config::{BattleEndAction, SimulationConfig},
+    faction::{faction_sizes, hostility_matrix},
+    readback::FactionReadback,
+    timestep::control::{SimulationControl, MIN_SPEED},
+    SimulationUniforms,
+};
+
+/// The victory condition that ended a battle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndReason {
+    //no two factions that are left are hostile to each other
+    LastFactionStanding,
+    //as above, but at least one faction was knocked out by the casualty threshold
+    Casualties,
+    TimeLimit,
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndReason::LastFactionStanding => write!(f, "last faction standing"),
+            EndReason::Casualties => write!(f, "casualty threshold"),
+            EndReason::TimeLimit => write!(f, "time limit"),
+        }
+    }
+}
+
+/// Inserted once the battle is over.
+#[derive(Resource, Clone, Debug)]
+pub struct BattleResult {
+    pub reason: EndReason,
+    //faction indices, several when allies win together and empty for a draw
+    pub winners: Vec<usize>,
+    pub tick: u64,
+    //living units per faction when the battle ended
+    pub survivors: Vec<u32>,
+    //pause state and speed before `victory.on_end` changed them, restored when an earlier tick is loaded
+    pub control: SimulationControl,
+}
+
+impl BattleResult {
+    /// A human readable summary, shown in the result panel and printed for headless runs.
+    pub fn summary(&self, config: &SimulationConfig) -> String {
+        let winner = if self.winners.is_empty() {
+            "draw".to_owned()
+        } else {
+            self.winners
+                .iter()
+                .map(|&faction| config.factions[faction].name.as_str())
+                .collect::<Vec<_>>()
+                .join(" and ")
+        };
+        let survivors = config
+            .factions
+            .iter()
+            .zip(&self.survivors)
+            .map(|(faction, survivors)| format!("{} {survivors}", faction.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "battle over after {} ticks ({})\n\
+             winner: {winner}\n\
+             survivors: {survivors}",
+            self.tick, self.reason,
+        )
+    }
+}
+
+/// Sent once when a victory condition is met.
+#[derive(Event, Clone, Debug)]
+pub struct BattleEnded(pub BattleResult);
+
+pub struct BattlePlugin;
+impl Plugin for BattlePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<BattleEnded>()
+            .add_systems(Update, check_victory);
+    }
+}
+
+//the faction counts arrive once per simulated second and at the time limit, so the end is noticed within a second
+fn check_victory(
+    mut commands: Commands,
+    config: Res<SimulationConfig>,
+    uniforms: Res<SimulationUniforms>,
+    (readback, result): (Res<FactionReadback>, Option<Res<BattleResult>>),
+    mut control: ResMut<SimulationControl>,
+    mut events: EventWriter<BattleEnded>,
+    mut spawned: Local<Vec<u32>>,
+) {
+    if result.is_some() || readback.counts.is_empty() {
+        return;
+    }
+    if spawned.is_empty() {
+        *spawned = faction_sizes(&uniforms.units, config.factions.len());
+    }
+    let victory = &config.victory;
+    let survivors: Vec<u32> = readback.counts.iter().map(|counts| counts.alive).collect();
+    let knocked_out = |faction: usize| {
+        victory.casualty_threshold.is_some_and(|threshold| {
+            let lost = spawned[faction].saturating_sub(survivors[faction]);
+            lost as f32 >= threshold * spawned[faction] as f32
+        })
+    };
+    let remaining: Vec<usize> = (0..survivors.len())
+        .filter(|&faction| survivors[faction] > 0 && !knocked_out(faction))
+        .collect();
+
+    let hostility = hostility_matrix(&config);
+    let count = config.factions.len();
+    let any_hostile = |factions: &[usize]| {
+        factions
+            .iter()
+            .any(|&a| factions.iter().any(|&b| hostility[a * count + b] != 0))
+    };
+
+    let (reason, winners, tick) = if !any_hostile(&remaining) {
+        let casualties =
+            (0..survivors.len()).any(|faction| survivors[faction] > 0 && knocked_out(faction));
+        let reason = if casualties {
+            EndReason::Casualties
+        } else {
+            EndReason::LastFactionStanding
+        };
+        (reason, remaining, readback.tick)
+    } else if let Some(limit) = victory.time_limit.filter(|&limit| readback.tick >= limit) {
+        let best = remaining.iter().map(|&faction| survivors[faction]).max();
+        let mut winners: Vec<usize> = remaining
+            .into_iter()
+            .filter(|&faction| Some(survivors[faction]) == best)
+            .collect();
+        //hostile factions tied for the most survivors
+        if any_hostile(&winners) {
+            winners.clear();
+        }
+        (EndReason::TimeLimit, winners, limit)
+    } else {
+        return;
+    };
+
+    let result = BattleResult {
+        reason,
+        winners,
+        tick,
+        survivors,
+        control: control.clone(),
+    };
+    println!("{}", result.summary(&config));
+    match victory.on_end {
+        BattleEndAction::Freeze => control.pause(),
+        BattleEndAction::SlowDown => control.set_speed(MIN_SPEED),
+    }
+    commands.insert_resource(result.clone());
+    events.send(BattleEnded(result));
+}
@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::config::{
+    ConfigError, SimulationBackend, SimulationConfig, SortBackend, WindowMode, DEFAULT_CONFIG_PATH,
+};
+use crate::replay::{Replay, ReplayError, ReplayRecorder};
+use crate::scenario::Scenario;
+use crate::snapshot::{Snapshot, SnapshotError};
+
+/// Command line overrides, applied on top of the config file.
+#[derive(Parser, Debug)]
+#[command(about = "Realtime large scale battle simulator running on GPU compute shaders")]
+pub struct Cli {
+    /// Config file to load before applying the other options
+    #[arg(long, default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+    /// Amount of units to spawn
+    #[arg(short = 'n', long)]
+    pub units: Option<u32>,
+    /// Seed for the spawning RNG
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// How the simulation is presented
+    #[arg(long, value_enum)]
+    pub mode: Option<WindowMode>,
+    /// Window resolution, for example 1920x1088
+    #[arg(long, value_parser = parse_resolution)]
+    pub resolution: Option<(u32, u32)>,
+    /// Simulation ticks per second
+    #[arg(long)]
+    pub tick_rate: Option<f32>,
+    /// Scenario file describing the armies to spawn
+    #[arg(long)]
+    pub scenario: Option<PathBuf>,
+    /// Algorithm that orders units by cell every tick
+    #[arg(long, value_enum)]
+    pub sort_backend: Option<SortBackend>,
+    /// Where the simulation steps run
+    #[arg(long, value_enum)]
+    pub backend: Option<SimulationBackend>,
+    /// Ends the battle after this many ticks, headless runs exit with status 0 once the battle is over
+    /// and print the result
+    #[arg(long)]
+    pub ticks: Option<u64>,
+    /// Snapshot to resume the battle from instead of starting a new one
+    #[arg(long)]
+    pub resume: Option<PathBuf>,
+    /// Records the battle into this replay file, one frame every `readback_interval` ticks
+    #[arg(long, conflicts_with = "resume")]
+    pub record: Option<PathBuf>,
+    /// Plays back a recorded replay instead of simulating
+    #[arg(long, conflicts_with_all = ["resume", "record"])]
+    pub replay: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Loads the config file and applies every option that was passed on the command line.
+    pub fn load_config(&self) -> Result<SimulationConfig, ConfigError> {
+        let mut config = SimulationConfig::load(&self.config)?;
+        if let Some(units) = self.units {
+            config.unit_count = units;
+        }
+        if let Some(seed) = self.seed {
+            config.seed = Some(seed);
+        }
+        if let Some(mode) = self.mode {
+            config.window_mode = mode;
+        }
+        if let Some(resolution) = self.resolution {
+            config.resolution = resolution;
+        }
+        if let Some(tick_rate) = self.tick_rate {
+            config.tick_rate = tick_rate;
+        }
+        if let Some(scenario) = &self.scenario {
+            config.scenario = Some(scenario.clone());
+        }
+        if let Some(sort_backend) = self.sort_backend {
+            config.sort_backend = sort_backend;
+        }
+        if let Some(backend) = self.backend {
+            config.backend = backend;
+        }
+        if let Some(ticks) = self.ticks {
+            config.victory.time_limit = Some(ticks);
+        }
+        //a headless run only exits when the battle ends, which a stalemate would never do without a limit
+        if config.window_mode == WindowMode::Headless && config.victory.time_limit.is_none() {
+            return Err(ConfigError::Invalid {
+                field: "ticks",
+                reason: "is required in headless mode, unless victory.time_limit is set".to_owned(),
+            });
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Loads the config and the scenario it points to, if any.
+    pub fn load_settings(&self) -> Result<(SimulationConfig, Option<Scenario>), ConfigError> {
+        let mut config = self.load_config()?;
+        //pick the seed up front so it can be reported and the run reproduced later
+        config.seed.get_or_insert_with(rand::random);
+        let Some(path) = config.scenario.clone() else {
+            return Ok((config, None));
+        };
+        //the scenario decides how many units there are, a count passed next to it would be ignored
+        if self.units.is_some() {
+            return Err(ConfigError::Invalid {
+                field: "units",
+                reason: "cannot be combined with a scenario, the armies set the unit count"
+                    .to_owned(),
+            });
+        }
+        let scenario = Scenario::load(path)?;
+        scenario.apply(&mut config)?;
+        config.validate()?;
+        scenario.validate(&config)?;
+        Ok((config, Some(scenario)))
+    }
+
+    /// Loads the snapshot passed with `--resume`, if any, and takes over its seed.
+    pub fn load_snapshot(
+        &self,
+        config: &mut SimulationConfig,
+    ) -> Result<Option<Snapshot>, SnapshotError> {
+        let Some(path) = &self.resume else {
+            return Ok(None);
+        };
+        let snapshot = Snapshot::load(path)?;
+        snapshot.check(config)?;
+        config.seed = Some(snapshot.header.seed);
+        Ok(Some(snapshot))
+    }
+
+    /// Loads the replay passed with `--replay`, if any, and takes over its unit count, tick rate and seed.
+    pub fn load_replay(
+        &self,
+        config: &mut SimulationConfig,
+    ) -> Result<Option<Replay>, ReplayError> {
+        let Some(path) = &self.replay else {
+            return Ok(None);
+        };
+        if config.window_mode == WindowMode::Headless {
+            return Err(ReplayError::Unsupported(
+                "a replay can only be played back with a window",
+            ));
+        }
+        let replay = Replay::load(path)?;
+        replay.check(config)?;
+        config.unit_count = replay.unit_count() as u32;
+        config.tick_rate = replay.header.tick_rate;
+        config.seed = Some(replay.header.seed);
+        config.validate()?;
+        Ok(Some(replay))
+    }
+
+    /// Creates the replay file passed with `--record`, if any.
+    pub fn start_recording(
+        &self,
+        config: &SimulationConfig,
+    ) -> Result<Option<ReplayRecorder>, ReplayError> {
+        self.record
+            .as_ref()
+            .map(|path| ReplayRecorder::create(path, config))
+            .transpose()
+    }
+}
+
+fn parse_resolution(input: &str) -> Result<(u32, u32), String> {
+    let (width, height) = input
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got `{input}`"))?;
+    let width = width
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid width `{width}`"))?;
+    let height = height
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid height `{height}`"))?;
+    Ok((width, height))
+}
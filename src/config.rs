@@ -0,0 +1,629 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_CONFIG_PATH: &str = "assets/config.ron";
+//the largest workgroup size every wgpu backend is guaranteed to support
+const MAX_WORKGROUP_SIZE: u32 = 256;
+pub const MAX_FACTIONS: usize = 16;
+
+/// Everything that shapes a run, loaded once at startup before the app is built.
+#[derive(Resource, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulationConfig {
+    pub unit_count: u32,
+    pub grid_size: i32,
+    pub world_size: (i32, i32),
+    pub display_factor: u32,
+    pub resolution: (u32, u32),
+    pub workgroup_size: u32,
+    pub window_mode: WindowMode,
+    pub tick_rate: f32,
+    //ticks run in a single frame at most, the simulation slows down instead of catching up past this
+    pub max_ticks_per_frame: u32,
+    //a random seed is picked when this is left empty
+    pub seed: Option<u64>,
+    pub scenario: Option<PathBuf>,
+    pub behaviour: BehaviourConfig,
+    pub factions: Vec<FactionConfig>,
+    //pairs of faction indices that will not attack each other
+    pub alliances: Vec<(u32, u32)>,
+    pub unit_types: Vec<UnitTypeConfig>,
+    //size of the projectile ring buffer, the oldest projectiles are overwritten once it is full
+    pub max_projectiles: u32,
+    //ticks between two compactions of the unit buffer, which drop dead units and shrink every dispatch
+    pub compaction_interval: u32,
+    pub sort_backend: SortBackend,
+    //where the simulation steps run, the renderer always stays on the GPU
+    pub backend: SimulationBackend,
+    //what happens to units that reach the edge of the world
+    pub boundary: BoundaryMode,
+    //ticks between two copies of the unit buffer back to the main world, 0 turns the readback off
+    pub readback_interval: u32,
+    //file written by quicksave and read by quickload
+    pub quicksave_path: PathBuf,
+    pub victory: VictoryConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UnitTypeConfig {
+    pub name: String,
+    pub max_health: i32,
+    pub melee_damage: i32,
+    pub speed: f32,
+    pub attack_range: f32,
+    pub protected_range: f32,
+    pub mass: f32,
+    //melee only when left empty
+    #[serde(default)]
+    pub ranged: Option<RangedConfig>,
+}
+
+/// Stats of a unit type that fires projectiles at targets between `kill_range` and `fire_range`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RangedConfig {
+    pub fire_range: f32,
+    pub projectile_speed: f32,
+    pub damage: i32,
+    pub reload_ticks: i32,
+    //every hostile unit this close to the impact point is hit
+    pub splash_radius: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FactionConfig {
+    pub name: String,
+    pub color: (f32, f32, f32),
+    //direction the faction marches in while it has no target, zero to march towards the origin
+    #[serde(default)]
+    pub advance: (f32, f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum WindowMode {
+    Windowed,
+    Borderless,
+    Headless,
+}
+
+/// How units are ordered by cell every tick.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum SortBackend {
+    //log²(n) compare and swap passes over the whole unit buffer
+    Bitonic,
+    //histogram of the cells, prefix sum and scatter, linear in the unit count
+    Counting,
+}
+
+/// Where the simulation steps run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum SimulationBackend {
+    //the compute passes in logic.wgsl
+    Gpu,
+    //the rayon port in cpu_sim, for machines where those passes can not run
+    Cpu,
+}
+
+/// Policy for units leaving the world, uploaded as an integer so the order has to match `logic.wgsl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BoundaryMode {
+    //stop at the edge
+    Clamp,
+    //bounce off the edge
+    Reflect,
+    //leave on one side and come back on the other, neighbours are found across the edges too
+    Wrap,
+    //units that leave are removed
+    Kill,
+}
+
+/// When a battle is over. It always ends once no two factions that are left are hostile to each other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VictoryConfig {
+    //fraction of its spawned units a faction can lose before it is out of the battle
+    pub casualty_threshold: Option<f32>,
+    //ticks after which the faction with the most survivors wins
+    pub time_limit: Option<u64>,
+    pub on_end: BattleEndAction,
+}
+
+/// What the simulation does once the battle is over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BattleEndAction {
+    Freeze,
+    //drop to the slowest speed and let the survivors play out
+    SlowDown,
+}
+
+impl Default for VictoryConfig {
+    fn default() -> Self {
+        Self {
+            casualty_threshold: None,
+            time_limit: None,
+            on_end: BattleEndAction::Freeze,
+        }
+    }
+}
+
+/// Steering and combat constants that are uploaded to `logic.wgsl` through the uniform buffer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BehaviourConfig {
+    pub targeting_factor: f32,
+    pub avoid_factor: f32,
+    pub kill_range: f32,
+    pub war_zone: f32,
+    //morale is kept between 0 and 1 and recovers by morale_recovery every tick
+    pub morale_recovery: f32,
+    //lost per tick for every hostile neighbour beyond the number of allied neighbours
+    pub outnumbered_penalty: f32,
+    //lost per tick for every allied neighbour that has just fallen
+    pub ally_death_penalty: f32,
+    //lost per tick at zero health, scaled down linearly for healthier units
+    pub wound_penalty: f32,
+    //units flee below rout_threshold and only turn around again above rally_threshold
+    pub rout_threshold: f32,
+    pub rally_threshold: f32,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            unit_count: 500000,
+            grid_size: 5,
+            world_size: (1920 * 2, 1080 * 2),
+            display_factor: 1,
+            resolution: (1920, 1088),
+            workgroup_size: 256,
+            window_mode: WindowMode::Borderless,
+            tick_rate: 8.0,
+            max_ticks_per_frame: 8,
+            seed: None,
+            scenario: None,
+            behaviour: BehaviourConfig::default(),
+            factions: vec![
+                FactionConfig {
+                    name: "Red".to_owned(),
+                    color: (1.0, 0.0, 0.0),
+                    advance: (1.0, 0.0),
+                },
+                FactionConfig {
+                    name: "Blue".to_owned(),
+                    color: (0.0, 0.0, 1.0),
+                    advance: (-1.0, 0.0),
+                },
+            ],
+            alliances: Vec::new(),
+            unit_types: vec![UnitTypeConfig {
+                name: "Infantry".to_owned(),
+                max_health: 4,
+                melee_damage: 1,
+                speed: 0.5,
+                attack_range: 20.0,
+                protected_range: 4.0,
+                mass: 1.0,
+                ranged: None,
+            }],
+            max_projectiles: 65536,
+            compaction_interval: 32,
+            sort_backend: SortBackend::Bitonic,
+            backend: SimulationBackend::Gpu,
+            boundary: BoundaryMode::Clamp,
+            readback_interval: 8,
+            quicksave_path: PathBuf::from("quicksave.snapshot"),
+            victory: VictoryConfig::default(),
+        }
+    }
+}
+
+impl Default for BehaviourConfig {
+    fn default() -> Self {
+        Self {
+            targeting_factor: 0.5,
+            avoid_factor: 2.0,
+            kill_range: 4.0,
+            war_zone: 5.0,
+            morale_recovery: 0.02,
+            outnumbered_penalty: 0.01,
+            ally_death_penalty: 0.1,
+            wound_penalty: 0.05,
+            rout_threshold: 0.25,
+            rally_threshold: 0.6,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: String,
+        error: std::io::Error,
+    },
+    Parse {
+        path: String,
+        error: ron::error::SpannedError,
+    },
+    Invalid {
+        field: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "could not read {path}: {error}"),
+            ConfigError::Parse { path, error } => write!(f, "could not parse {path}: {error}"),
+            ConfigError::Invalid { field, reason } => write!(f, "invalid `{field}`: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(field: &'static str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.into(),
+    }
+}
+
+impl SimulationConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.display().to_string(),
+            error,
+        })?;
+        let config: SimulationConfig =
+            ron::from_str(&text).map_err(|error| ConfigError::Parse {
+                path: path.display().to_string(),
+                error,
+            })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks every value that would otherwise only fail once it reaches the GPU.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.grid_size <= 0 {
+            return Err(invalid("grid_size", "must be greater than 0"));
+        }
+        if self.world_size.0 <= 0 || self.world_size.1 <= 0 {
+            return Err(invalid(
+                "world_size",
+                "both dimensions must be greater than 0",
+            ));
+        }
+        if self.world_size.0 % self.grid_size != 0 || self.world_size.1 % self.grid_size != 0 {
+            return Err(invalid(
+                "world_size",
+                format!(
+                    "both dimensions must be multiples of grid_size ({})",
+                    self.grid_size
+                ),
+            ));
+        }
+        if self.display_factor == 0 {
+            return Err(invalid("display_factor", "must be greater than 0"));
+        }
+        let size = self.size();
+        if size.0 == 0 || size.1 == 0 || !size.0.is_multiple_of(32) || !size.1.is_multiple_of(32) {
+            return Err(invalid(
+                "resolution",
+                "resolution / display_factor must be a non-zero multiple of 32 in both dimensions",
+            ));
+        }
+        //one thread per faction clears and adds up the faction counts
+        if !self.workgroup_size.is_power_of_two()
+            || self.workgroup_size < MAX_FACTIONS as u32
+            || self.workgroup_size > MAX_WORKGROUP_SIZE
+        {
+            return Err(invalid(
+                "workgroup_size",
+                format!("must be a power of two between {MAX_FACTIONS} and {MAX_WORKGROUP_SIZE}"),
+            ));
+        }
+        if self.unit_count == 0 || self.unit_count > 1 << 26 {
+            return Err(invalid("unit_count", "must be between 1 and 67108864"));
+        }
+        if !self.tick_rate.is_finite() || self.tick_rate <= 0.0 {
+            return Err(invalid(
+                "tick_rate",
+                "must be a finite number greater than 0",
+            ));
+        }
+        if self.factions.is_empty() || self.factions.len() > MAX_FACTIONS {
+            return Err(invalid(
+                "factions",
+                format!("must list between 1 and {MAX_FACTIONS} factions"),
+            ));
+        }
+        for faction in &self.factions {
+            let (r, g, b) = faction.color;
+            if [r, g, b].iter().any(|c| !(0.0..=1.0).contains(c)) {
+                return Err(invalid(
+                    "factions",
+                    format!("colour of `{}` must be in the 0..1 range", faction.name),
+                ));
+            }
+            if !faction.advance.0.is_finite() || !faction.advance.1.is_finite() {
+                return Err(invalid(
+                    "factions",
+                    format!("advance of `{}` must be finite", faction.name),
+                ));
+            }
+        }
+        for &(a, b) in &self.alliances {
+            if a as usize >= self.factions.len() || b as usize >= self.factions.len() {
+                return Err(invalid(
+                    "alliances",
+                    format!(
+                        "({a}, {b}) refers to a faction that does not exist, there are {} factions",
+                        self.factions.len()
+                    ),
+                ));
+            }
+        }
+        self.behaviour.validate()?;
+        if let Some(threshold) = self.victory.casualty_threshold {
+            if !(threshold > 0.0 && threshold <= 1.0) {
+                return Err(invalid(
+                    "victory.casualty_threshold",
+                    "must be greater than 0 and at most 1",
+                ));
+            }
+        }
+        if self.victory.time_limit == Some(0) {
+            return Err(invalid("victory.time_limit", "must be at least 1 tick"));
+        }
+        if self.unit_types.is_empty() {
+            return Err(invalid("unit_types", "must list at least one unit type"));
+        }
+        for unit_type in &self.unit_types {
+            unit_type.validate(&self.behaviour)?;
+        }
+        //a search block wider than the grid would visit wrapped cells twice
+        let (width, height) = self.hash_size();
+        if 2 * self.search_rings() + 1 > width.min(height) {
+            return Err(invalid(
+                "grid_size",
+                format!(
+                    "is too small for the largest unit range, {} rings of cells do not fit in the {width}x{height} grid",
+                    self.search_rings()
+                ),
+            ));
+        }
+        if self.max_projectiles == 0 || self.max_projectiles > 1 << 24 {
+            return Err(invalid("max_projectiles", "must be between 1 and 16777216"));
+        }
+        if self.max_ticks_per_frame == 0 {
+            return Err(invalid("max_ticks_per_frame", "must be at least 1"));
+        }
+        if self.compaction_interval == 0 {
+            return Err(invalid("compaction_interval", "must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Number of entries in the cell table: every grid cell plus the overflow cell for units outside the grid.
+    pub fn cell_count(&self) -> u32 {
+        let (width, height) = self.hash_size();
+        (width * height) as u32 + 1
+    }
+
+    /// Number of counting sort buckets: one per cell plus one for dead units.
+    pub fn bucket_count(&self) -> u32 {
+        self.cell_count() + 1
+    }
+
+    /// The most rings of cells any neighbour search walks, matching `search_rings` in neighbours.wgsl.
+    pub fn search_rings(&self) -> i32 {
+        self.unit_types
+            .iter()
+            .map(|unit_type| {
+                let splash = unit_type
+                    .ranged
+                    .as_ref()
+                    .map_or(0.0, |ranged| ranged.splash_radius);
+                let range = unit_type
+                    .attack_range
+                    .max(unit_type.protected_range)
+                    .max(splash);
+                ((range / self.grid_size as f32).ceil() as i32).max(1)
+            })
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Looks up a unit type index by its name.
+    pub fn unit_type_index(&self, name: &str) -> Option<usize> {
+        self.unit_types
+            .iter()
+            .position(|unit_type| unit_type.name == name)
+    }
+
+    /// The seed used for spawning, resolved when the config is loaded.
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or_default()
+    }
+
+    /// A human readable summary of the effective configuration, printed at startup.
+    pub fn summary(&self) -> String {
+        let seed = match self.seed {
+            Some(seed) => seed.to_string(),
+            None => "random".to_owned(),
+        };
+        let scenario = match &self.scenario {
+            Some(path) => path.display().to_string(),
+            None => "none".to_owned(),
+        };
+        format!(
+            "units: {}\n\
+             seed: {seed}\n\
+             mode: {:?}\n\
+             resolution: {}x{}\n\
+             tick rate: {} ticks/s\n\
+             backend: {:?}\n\
+             sort: {:?}\n\
+             scenario: {scenario}\n\
+             world: {}x{}, grid size {}, {} search rings, {:?} boundary\n\
+             factions: {}",
+            self.count(),
+            self.window_mode,
+            self.resolution.0,
+            self.resolution.1,
+            self.tick_rate,
+            self.backend,
+            self.sort_backend,
+            self.world_size.0,
+            self.world_size.1,
+            self.grid_size,
+            self.search_rings(),
+            self.boundary,
+            self.factions
+                .iter()
+                .map(|faction| faction.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+
+    /// Ticks in one simulated second, the interval of the faction counters.
+    pub fn ticks_per_second(&self) -> u64 {
+        (self.tick_rate.round() as u64).max(1)
+    }
+
+    /// The amount of units that are simulated.
+    pub fn count(&self) -> i32 {
+        self.unit_count as i32
+    }
+
+    /// The length of the unit buffer, padded with sentinel units to a power of two for the bitonic sort.
+    pub fn capacity(&self) -> i32 {
+        self.unit_count
+            .next_power_of_two()
+            .max(2 * self.workgroup_size) as i32
+    }
+
+    /// The size of the render texture.
+    pub fn size(&self) -> (u32, u32) {
+        (
+            self.resolution.0 / self.display_factor,
+            self.resolution.1 / self.display_factor,
+        )
+    }
+
+    /// The amount of spatial hash cells along each axis.
+    pub fn hash_size(&self) -> (i32, i32) {
+        (
+            self.world_size.0 / self.grid_size,
+            self.world_size.1 / self.grid_size,
+        )
+    }
+}
+
+impl BehaviourConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let values = [
+            ("behaviour.targeting_factor", self.targeting_factor),
+            ("behaviour.avoid_factor", self.avoid_factor),
+            ("behaviour.kill_range", self.kill_range),
+            ("behaviour.war_zone", self.war_zone),
+            ("behaviour.morale_recovery", self.morale_recovery),
+            ("behaviour.outnumbered_penalty", self.outnumbered_penalty),
+            ("behaviour.ally_death_penalty", self.ally_death_penalty),
+            ("behaviour.wound_penalty", self.wound_penalty),
+            ("behaviour.rout_threshold", self.rout_threshold),
+            ("behaviour.rally_threshold", self.rally_threshold),
+        ];
+        for (field, value) in values {
+            if !value.is_finite() || value < 0.0 {
+                return Err(invalid(field, "must be a finite, non-negative number"));
+            }
+        }
+        if self.rally_threshold > 1.0 || self.rout_threshold >= self.rally_threshold {
+            return Err(invalid(
+                "behaviour.rally_threshold",
+                "must be at most 1 and greater than behaviour.rout_threshold",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl UnitTypeConfig {
+    fn validate(&self, behaviour: &BehaviourConfig) -> Result<(), ConfigError> {
+        let name = &self.name;
+        if self.max_health <= 0 {
+            return Err(invalid(
+                "unit_types",
+                format!("max_health of `{name}` must be greater than 0"),
+            ));
+        }
+        if self.melee_damage < 0 {
+            return Err(invalid(
+                "unit_types",
+                format!("melee_damage of `{name}` must not be negative"),
+            ));
+        }
+        let values = [
+            ("speed", self.speed),
+            ("attack_range", self.attack_range),
+            ("protected_range", self.protected_range),
+            ("mass", self.mass),
+        ];
+        for (field, value) in values {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(invalid(
+                    "unit_types",
+                    format!("{field} of `{name}` must be a finite number greater than 0"),
+                ));
+            }
+        }
+        if behaviour.kill_range > self.attack_range {
+            return Err(invalid(
+                "unit_types",
+                format!("attack_range of `{name}` must not be smaller than behaviour.kill_range"),
+            ));
+        }
+        if let Some(ranged) = &self.ranged {
+            let values = [
+                ("fire_range", ranged.fire_range),
+                ("projectile_speed", ranged.projectile_speed),
+                ("splash_radius", ranged.splash_radius),
+            ];
+            for (field, value) in values {
+                if !value.is_finite() || value <= 0.0 {
+                    return Err(invalid(
+                        "unit_types",
+                        format!(
+                            "ranged.{field} of `{name}` must be a finite number greater than 0"
+                        ),
+                    ));
+                }
+            }
+            if ranged.fire_range > self.attack_range {
+                return Err(invalid(
+                    "unit_types",
+                    format!(
+                        "ranged.fire_range of `{name}` must not be larger than its attack_range"
+                    ),
+                ));
+            }
+            if ranged.damage < 0 || ranged.reload_ticks < 1 {
+                return Err(invalid(
+                    "unit_types",
+                    format!("ranged.damage of `{name}` must not be negative and reload_ticks must be at least 1"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
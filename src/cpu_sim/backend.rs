@@ -0,0 +1,113 @@
+use bevy::{
+    prelude::*,
+    render::{renderer::RenderQueue, Extract, RenderApp},
+};
+
+use super::simulation::CpuSimulation;
+use crate::{
+    config::SimulationConfig,
+    helpers::helpers::write_buffer,
+    readback::{FactionReadback, ProjectileReadback, ReadbackRequests, UnitReadback},
+    timestep::clock::SimulationClock,
+    DispatchBuffer, LiveBuffer, ProjectileBuffer, SimulationUniforms, UnitBuffer,
+};
+
+/// Runs the simulation on the CPU instead of the logic node and uploads the result for rendering.
+///
+/// The readbacks are published straight from the CPU state on the same intervals as the GPU readback.
+pub struct CpuSimulationPlugin;
+impl Plugin for CpuSimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostStartup, start_simulation)
+            .add_systems(Update, run_ticks);
+        app.sub_app_mut(RenderApp)
+            .add_systems(ExtractSchedule, upload_units);
+    }
+}
+
+//there are no pipelines to wait for, so the clock starts as soon as the units are spawned
+fn start_simulation(
+    mut commands: Commands,
+    config: Res<SimulationConfig>,
+    uniforms: Res<SimulationUniforms>,
+    mut clock: ResMut<SimulationClock>,
+) {
+    commands.insert_resource(CpuSimulation::from_uniforms(&config, &uniforms));
+    clock.start();
+}
+
+fn run_ticks(
+    config: Res<SimulationConfig>,
+    clock: Res<SimulationClock>,
+    requests: Res<ReadbackRequests>,
+    mut simulation: ResMut<CpuSimulation>,
+    mut unit_readback: ResMut<UnitReadback>,
+    (mut faction_readback, mut projectile_readback): (
+        ResMut<FactionReadback>,
+        ResMut<ProjectileReadback>,
+    ),
+    mut served: Local<u64>,
+) {
+    for tick in clock.frame_ticks() {
+        simulation.step(tick.is_multiple_of(config.compaction_interval as u64));
+    }
+    let due = |interval: u64| {
+        interval != 0
+            && clock
+                .frame_ticks()
+                .any(|tick| tick.is_multiple_of(interval))
+    };
+    let requested = *served < requests.requested();
+    *served = requests.requested();
+    if due(config.readback_interval as u64) || requested {
+        *unit_readback = UnitReadback {
+            tick: clock.tick(),
+            live: simulation.live(),
+            units: simulation.units().to_vec(),
+        };
+    }
+    let time_limit = config
+        .victory
+        .time_limit
+        .is_some_and(|limit| clock.frame_ticks().contains(&limit));
+    if due(config.ticks_per_second()) || time_limit || requested {
+        *faction_readback = FactionReadback {
+            tick: clock.tick(),
+            counts: simulation.faction_counts(),
+        };
+    }
+    if requested {
+        *projectile_readback = ProjectileReadback {
+            tick: clock.tick(),
+            counter: simulation.projectile_counter(),
+            projectiles: simulation.projectiles().to_vec(),
+        };
+    }
+}
+
+//writes the units, the live count and the projectiles over the buffers the logic node would have filled
+fn upload_units(
+    simulation: Extract<Option<Res<CpuSimulation>>>,
+    render_queue: Res<RenderQueue>,
+    unit_buffer: Res<UnitBuffer>,
+    live_buffer: Res<LiveBuffer>,
+    dispatch_buffer: Res<DispatchBuffer>,
+    projectile_buffer: Res<ProjectileBuffer>,
+) {
+    let Some(simulation) = simulation.as_ref() else {
+        return;
+    };
+    //the buffers are created on the first render, they start out with the spawned units
+    if !simulation.is_changed() || unit_buffer.0.is_empty() {
+        return;
+    }
+    write_buffer(&render_queue, &unit_buffer.0[0], simulation.units());
+    let live = simulation.live();
+    write_buffer(&render_queue, &live_buffer.0[0], &live);
+    write_buffer(&render_queue, &dispatch_buffer.0[0], &live);
+    write_buffer(
+        &render_queue,
+        &projectile_buffer.0[0],
+        simulation.projectiles(),
+    );
+}
@@ -0,0 +1,579 @@
+use bevy::prelude::*;
+use rayon::prelude::*;
+
+use crate::{
+    config::{BoundaryMode, SimulationConfig},
+    faction::{faction_table, hostility_matrix, Faction, FactionCounts},
+    grid::CellRange,
+    projectile::Projectile,
+    unit::{LiveUnits, Unit, UnitCombat},
+    unit_type::{unit_type_table, UnitTypeStats},
+    SimulationUniforms, UniformData,
+};
+
+//same as FALLEN in logic.wgsl
+const FALLEN: i32 = -2;
+
+/// The simulation step of `logic.wgsl` ported to the CPU, pass for pass, on the same units and uniforms.
+///
+/// Units are ordered by cell and then by id every tick, like the counting sort. Everything the shader
+/// accumulates with atomics is gathered per unit and applied in unit order, so a tick gives the same
+/// result on any number of threads. Float results can still drift from the GPU in the last bits.
+#[derive(Resource)]
+pub struct CpuSimulation {
+    //live units only, there is no sentinel padding on the CPU
+    units: Vec<Unit>,
+    cells: Vec<CellRange>,
+    combat: Vec<UnitCombat>,
+    projectiles: Vec<Projectile>,
+    projectile_counter: u32,
+    faction_counts: Vec<FactionCounts>,
+    data: UniformData,
+    factions: Vec<Faction>,
+    hostility: Vec<u32>,
+    unit_types: Vec<UnitTypeStats>,
+    capacity: u32,
+    workgroup_size: u32,
+}
+
+//what one unit's update does to other units, resolved after every unit has been updated
+#[derive(Default)]
+struct UpdateEffects {
+    //slot of the target and the id claiming it
+    claim: Option<(usize, i32)>,
+    //slot of the target, damage and the attacking faction
+    strike: Option<(usize, i32, i32)>,
+    projectile: Option<Projectile>,
+    //damage the unit deals to itself, set when it leaves the world with the Kill boundary
+    self_damage: i32,
+}
+
+impl CpuSimulation {
+    /// Starts from the spawned units, sentinel padding is dropped.
+    pub fn new(config: &SimulationConfig, units: &[Unit], data: UniformData) -> Self {
+        let units: Vec<Unit> = units.iter().copied().filter(|unit| unit.id >= 0).collect();
+        Self {
+            combat: vec![UnitCombat::default(); units.len()],
+            units,
+            cells: vec![CellRange::default(); config.cell_count() as usize],
+            projectiles: vec![Projectile::default(); config.max_projectiles as usize],
+            projectile_counter: 0,
+            faction_counts: vec![FactionCounts::default(); config.factions.len()],
+            data,
+            factions: faction_table(config),
+            hostility: hostility_matrix(config),
+            unit_types: unit_type_table(config),
+            capacity: config.capacity() as u32,
+            workgroup_size: config.workgroup_size,
+        }
+    }
+
+    /// Starts from the units and uniforms the app spawned.
+    pub fn from_uniforms(config: &SimulationConfig, uniforms: &SimulationUniforms) -> Self {
+        Self::new(config, &uniforms.units, uniforms.data.clone().unwrap())
+    }
+
+    /// Replaces the battle with saved units, kills and projectiles in flight.
+    pub fn restore(
+        &mut self,
+        units: &[Unit],
+        kills: &[u32],
+        projectiles: &[Projectile],
+        projectile_counter: u32,
+    ) {
+        self.units = units.iter().copied().filter(|unit| unit.id >= 0).collect();
+        self.combat = vec![UnitCombat::default(); self.units.len()];
+        self.projectiles.copy_from_slice(projectiles);
+        self.projectile_counter = projectile_counter;
+        for (counts, &kills) in self.faction_counts.iter_mut().zip(kills) {
+            counts.kills = kills;
+        }
+    }
+
+    /// Units in their order after the last tick, including units that died since the last compaction.
+    pub fn units(&self) -> &[Unit] {
+        &self.units
+    }
+
+    /// The cell table built by the last `hash_indices`.
+    pub fn cells(&self) -> &[CellRange] {
+        &self.cells
+    }
+
+    pub fn projectiles(&self) -> &[Projectile] {
+        &self.projectiles
+    }
+
+    /// Projectiles fired so far, the next one goes into this slot modulo the projectile count.
+    pub fn projectile_counter(&self) -> u32 {
+        self.projectile_counter
+    }
+
+    /// The live count and dispatch arguments the GPU would hold for the current units.
+    pub fn live(&self) -> LiveUnits {
+        LiveUnits::new(self.units.len() as u32, self.capacity, self.workgroup_size)
+    }
+
+    /// Living units and kills of every faction, like `count_factions`.
+    pub fn faction_counts(&self) -> Vec<FactionCounts> {
+        let count = self.faction_counts.len();
+        let alive = self
+            .units
+            .par_iter()
+            .filter(|unit| unit.health > 0)
+            .fold(
+                || vec![0; count],
+                |mut alive, unit| {
+                    alive[unit.faction as usize] += 1;
+                    alive
+                },
+            )
+            .reduce(
+                || vec![0; count],
+                |mut a, b| {
+                    a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                    a
+                },
+            );
+        self.faction_counts
+            .iter()
+            .zip(alive)
+            .map(|(counts, alive)| FactionCounts {
+                alive,
+                kills: counts.kills,
+            })
+            .collect()
+    }
+
+    /// Runs one tick, in the same order as the logic node. The passes are public so tests can check
+    /// the state in between them.
+    pub fn step(&mut self, compact: bool) {
+        self.hash();
+        self.sort();
+        self.hash_indices();
+        self.update();
+        self.update_projectiles();
+        self.apply_damage();
+        if compact {
+            self.compact();
+        }
+    }
+
+    /// Snapshots the positions of the living units and assigns them to cells.
+    pub fn hash(&mut self) {
+        let data = &self.data;
+        self.units.par_iter_mut().for_each(|unit| {
+            if unit.health <= 0 {
+                if unit.attack_id == FALLEN {
+                    unit.attack_id = -1;
+                } else if unit.hash_id >= 0 {
+                    unit.hash_id = -999;
+                }
+                return;
+            }
+            unit.previous_state = unit.current_state;
+            unit.hash_id = compute_hash_id(data, unit.current_state);
+        });
+    }
+
+    /// Orders the units by cell, and by id inside a cell.
+    pub fn sort(&mut self) {
+        self.units
+            .par_sort_unstable_by_key(|unit| (unit.hash_id, unit.id));
+    }
+
+    /// Rebuilds the cell table from the sorted units.
+    pub fn hash_indices(&mut self) {
+        self.cells.fill(CellRange::default());
+        let count = self.units.len();
+        for index in 0..count {
+            let key = self.units[index].hash_id;
+            if key < 0 || key as usize >= self.cells.len() {
+                continue;
+            }
+            if index == 0 || self.units[index - 1].hash_id != key {
+                self.cells[key as usize].start = index as u32;
+            }
+            if index + 1 == count || self.units[index + 1].hash_id != key {
+                self.cells[key as usize].end = index as u32 + 1;
+            }
+        }
+    }
+
+    /// Moves every living unit, picks targets, strikes and fires.
+    pub fn update(&mut self) {
+        let (units, effects): (Vec<Unit>, Vec<UpdateEffects>) = (0..self.units.len())
+            .into_par_iter()
+            .map(|index| self.update_unit(index))
+            .unzip();
+        self.units = units;
+        for (index, effects) in effects.into_iter().enumerate() {
+            if let Some((slot, id)) = effects.claim {
+                let attacker = &mut self.combat[slot].attacker;
+                if *attacker == -1 || id < *attacker {
+                    *attacker = id;
+                }
+            }
+            if let Some((slot, damage, faction)) = effects.strike {
+                self.combat[slot].damage += damage;
+                self.combat[slot].hits |= 1 << faction;
+            }
+            if let Some(projectile) = effects.projectile {
+                let slot = self.projectile_counter as usize % self.projectiles.len();
+                self.projectile_counter = self.projectile_counter.wrapping_add(1);
+                self.projectiles[slot] = projectile;
+            }
+            self.combat[index].damage += effects.self_damage;
+        }
+    }
+
+    //reads neighbours only through fields that update does not write, so every unit sees the same snapshot
+    fn update_unit(&self, index: usize) -> (Unit, UpdateEffects) {
+        let data = &self.data;
+        let mut unit = self.units[index];
+        let mut effects = UpdateEffects::default();
+        if unit.health <= 0 {
+            return (unit, effects);
+        }
+        let mut current_state = unit.current_state;
+        let mut velocity = unit.velocity;
+        let stats = self.unit_types[unit.unit_type as usize];
+        let mut closest = 1000.0;
+        let attack_id = unit.attack_id;
+        let mut new_attack_id = -1;
+        let mut enemy_index = -1;
+        let mut allies = 0;
+        let mut enemies = 0;
+        let mut fallen_allies = 0;
+        let mut flee = Vec2::ZERO;
+
+        let rings = search_rings(
+            stats.attack_range.max(stats.protected_range),
+            data.grid_size,
+        );
+        let scan = if unit.hash_id == overflow_cell(data) {
+            0
+        } else {
+            ring_cell_count(rings)
+        };
+        let cell = IVec2::new(
+            unit.hash_id % data.grid_width,
+            unit.hash_id / data.grid_width,
+        );
+        for j in 0..scan {
+            let range = self.cell_range(ring_cell(cell, rings, j));
+            for i in range.start as usize..range.end as usize {
+                if i == index {
+                    continue;
+                }
+                let other = &self.units[i];
+                let offset = displacement(data, other.previous_state, current_state);
+                let dist = offset.length();
+                let hostile = self.is_hostile(unit.faction, other.faction);
+                if other.health <= 0 {
+                    if !hostile {
+                        fallen_allies += 1;
+                    }
+                    continue;
+                }
+                if hostile {
+                    enemies += 1;
+                    flee += offset / (dist * dist).max(0.0001);
+                } else {
+                    allies += 1;
+                }
+                if dist < stats.protected_range {
+                    let e_mass = self.unit_types[other.unit_type as usize].mass;
+                    let avoid = offset.normalize()
+                        * data.avoid_factor
+                        * (stats.protected_range / dist)
+                        * (2.0 * e_mass / (stats.mass + e_mass));
+                    velocity += avoid;
+                }
+                if attack_id == -1 && hostile {
+                    if dist < closest && dist < stats.attack_range {
+                        new_attack_id = other.id;
+                        enemy_index = i as i32;
+                        closest = dist;
+                    }
+                } else if attack_id != -1 && other.id == attack_id {
+                    new_attack_id = attack_id;
+                    enemy_index = i as i32;
+                }
+            }
+        }
+
+        let wounds = 1.0 - unit.health as f32 / stats.max_health as f32;
+        let morale = (unit.morale + data.morale_recovery
+            - (enemies - allies).max(0) as f32 * data.outnumbered_penalty
+            - fallen_allies as f32 * data.ally_death_penalty
+            - wounds * data.wound_penalty)
+            .clamp(0.0, 1.0);
+        let mut routing = unit.routing;
+        if routing == 0 && morale < data.rout_threshold {
+            routing = 1;
+        } else if routing == 1 && morale > data.rally_threshold {
+            routing = 0;
+        }
+
+        let mut reload = unit.reload - 1;
+        if routing == 1 {
+            new_attack_id = -1;
+            if enemies > 0 {
+                velocity += flee.normalize_or_zero() * data.targeting_factor;
+            }
+        } else if new_attack_id != -1 {
+            let enemy_index = enemy_index as usize;
+            effects.claim = Some((enemy_index, unit.id));
+            let e_position = self.units[enemy_index].previous_state;
+            let to_enemy = displacement(data, current_state, e_position);
+            let dist = to_enemy.length();
+            if dist >= data.kill_range && dist < stats.fire_range {
+                if reload <= 0 {
+                    effects.projectile = Some(fire(
+                        current_state,
+                        current_state + to_enemy,
+                        unit.faction,
+                        &stats,
+                    ));
+                    reload = stats.reload_ticks;
+                }
+                velocity *= 0.5;
+            } else {
+                velocity += to_enemy.normalize() * data.targeting_factor;
+            }
+            if dist < data.kill_range {
+                effects.strike = Some((enemy_index, stats.melee_damage, unit.faction));
+            }
+        } else {
+            let advance = self.factions[unit.faction as usize].advance;
+            let world_size = world_extent(data);
+            let progress = current_state.dot(advance).abs();
+            if progress < data.war_zone || progress > advance.abs().dot(world_size) * 0.45 {
+                velocity += (Vec2::ZERO - current_state).normalize() * data.targeting_factor;
+            } else {
+                velocity += advance * data.targeting_factor;
+            }
+        }
+
+        velocity = velocity.normalize() * velocity.length().clamp(-stats.speed, stats.speed);
+
+        current_state += velocity;
+
+        let half_world = world_extent(data) / 2.0;
+        match data.boundary_mode {
+            mode if mode == BoundaryMode::Reflect as i32 => {
+                let overshoot = current_state.abs() - half_world;
+                if overshoot.x > 0.0 {
+                    current_state.x = current_state.x.signum() * (half_world.x - overshoot.x);
+                    velocity.x = -velocity.x;
+                }
+                if overshoot.y > 0.0 {
+                    current_state.y = current_state.y.signum() * (half_world.y - overshoot.y);
+                    velocity.y = -velocity.y;
+                }
+                current_state = current_state.clamp(-half_world, half_world);
+            }
+            mode if mode == BoundaryMode::Wrap as i32 => {
+                current_state = wrap_position(data, current_state);
+            }
+            mode if mode == BoundaryMode::Kill as i32 => {
+                if current_state.abs().cmpgt(half_world).any() {
+                    effects.self_damage = unit.health;
+                }
+            }
+            _ => {
+                current_state = current_state.clamp(-half_world, half_world);
+            }
+        }
+
+        unit.attack_id = new_attack_id;
+        unit.current_state = current_state;
+        unit.velocity = velocity;
+        unit.reload = reload.max(0);
+        unit.morale = morale;
+        unit.routing = routing;
+        (unit, effects)
+    }
+
+    /// Moves the projectiles and splashes the ones that land.
+    pub fn update_projectiles(&mut self) {
+        let data = &self.data;
+        let units = &self.units;
+        let cells = &self.cells;
+        let hostility = &self.hostility;
+        let hits: Vec<(usize, i32, i32)> = self
+            .projectiles
+            .par_iter_mut()
+            .filter(|projectile| projectile.ticks_left > 0)
+            .flat_map_iter(|projectile| {
+                projectile.previous_position = projectile.position;
+                projectile.position += projectile.velocity;
+                projectile.ticks_left -= 1;
+                let mut hits = Vec::new();
+                if projectile.ticks_left > 0 {
+                    return hits;
+                }
+                let mut impact = projectile.impact;
+                if data.boundary_mode == BoundaryMode::Wrap as i32 {
+                    impact = wrap_position(data, impact);
+                }
+                let cell = cell_coords(data, impact);
+                let rings = search_rings(projectile.splash_radius, data.grid_size);
+                for j in 0..ring_cell_count(rings) {
+                    let range = cell_range(data, cells, ring_cell(cell, rings, j));
+                    let start = range.start as usize;
+                    for (i, unit) in units[start..range.end as usize].iter().enumerate() {
+                        let i = start + i;
+                        let hostile = hostility
+                            [(projectile.faction * data.faction_count + unit.faction) as usize]
+                            != 0;
+                        if unit.health > 0
+                            && hostile
+                            && displacement(data, impact, unit.current_state).length()
+                                < projectile.splash_radius
+                        {
+                            hits.push((i, projectile.damage, projectile.faction));
+                        }
+                    }
+                }
+                hits
+            })
+            .collect();
+        for (slot, damage, faction) in hits {
+            self.combat[slot].damage += damage;
+            self.combat[slot].hits |= 1 << faction;
+        }
+    }
+
+    /// Resolves the damage and target claims of this tick.
+    pub fn apply_damage(&mut self) {
+        let kills: Vec<u32> = self
+            .units
+            .par_iter_mut()
+            .zip(self.combat.par_iter_mut())
+            .filter_map(|(unit, combat)| {
+                let UnitCombat {
+                    damage,
+                    attacker,
+                    hits,
+                } = std::mem::take(combat);
+                if unit.health <= 0 {
+                    return None;
+                }
+                unit.health -= damage;
+                if unit.health <= 0 {
+                    unit.attack_id = FALLEN;
+                    //shared kills go to the lowest faction, like on the GPU
+                    return (hits != 0).then(|| hits.trailing_zeros());
+                }
+                if attacker != -1 && unit.attack_id == -1 && unit.routing == 0 {
+                    unit.attack_id = attacker;
+                }
+                None
+            })
+            .collect();
+        for faction in kills {
+            self.faction_counts[faction as usize].kills += 1;
+        }
+    }
+
+    /// Drops the dead units, keeping the order of the rest like `compact_scatter`.
+    pub fn compact(&mut self) {
+        self.units
+            .retain(|unit| unit.health > 0 || unit.attack_id == FALLEN);
+        self.combat.truncate(self.units.len());
+    }
+
+    fn is_hostile(&self, faction: i32, other: i32) -> bool {
+        self.hostility[(faction * self.data.faction_count + other) as usize] != 0
+    }
+
+    fn cell_range(&self, cell: IVec2) -> CellRange {
+        cell_range(&self.data, &self.cells, cell)
+    }
+}
+
+fn fire(position: Vec2, impact: Vec2, faction: i32, stats: &UnitTypeStats) -> Projectile {
+    let ticks = (((impact - position).length() / stats.projectile_speed).ceil() as i32).max(1);
+    Projectile {
+        previous_position: position,
+        position,
+        velocity: (impact - position) / ticks as f32,
+        impact,
+        damage: stats.projectile_damage,
+        faction,
+        splash_radius: stats.splash_radius,
+        ticks_left: ticks,
+    }
+}
+
+fn world_extent(data: &UniformData) -> Vec2 {
+    Vec2::new(data.grid_width as f32, data.grid_height as f32) * data.grid_size as f32
+}
+
+fn overflow_cell(data: &UniformData) -> i32 {
+    data.grid_width * data.grid_height
+}
+
+fn cell_coords(data: &UniformData, position: Vec2) -> IVec2 {
+    let grid = IVec2::new(data.grid_width, data.grid_height);
+    let cell = (position / data.grid_size as f32 + grid.as_vec2() / 2.0)
+        .floor()
+        .as_ivec2();
+    //the far edges are still inside the world, but floor to one past the last cell
+    if position.abs().cmple(world_extent(data) / 2.0).all() {
+        return cell.min(grid - 1);
+    }
+    cell
+}
+
+fn compute_hash_id(data: &UniformData, position: Vec2) -> i32 {
+    let cell = cell_coords(data, position);
+    if cell.x < 0 || cell.y < 0 || cell.x >= data.grid_width || cell.y >= data.grid_height {
+        return overflow_cell(data);
+    }
+    cell.x + cell.y * data.grid_width
+}
+
+fn wrap_position(data: &UniformData, position: Vec2) -> Vec2 {
+    let size = world_extent(data);
+    position - size * ((position + size / 2.0) / size).floor()
+}
+
+fn displacement(data: &UniformData, origin: Vec2, target_position: Vec2) -> Vec2 {
+    let mut offset = target_position - origin;
+    if data.boundary_mode == BoundaryMode::Wrap as i32 {
+        let size = world_extent(data);
+        //WGSL rounds halfway cases to even
+        let turns = offset / size;
+        offset -= size * Vec2::new(turns.x.round_ties_even(), turns.y.round_ties_even());
+    }
+    offset
+}
+
+fn cell_range(data: &UniformData, cells: &[CellRange], cell: IVec2) -> CellRange {
+    let mut cell = cell;
+    if data.boundary_mode == BoundaryMode::Wrap as i32 {
+        cell = cell.rem_euclid(IVec2::new(data.grid_width, data.grid_height));
+    }
+    if cell.x < 0 || cell.y < 0 || cell.x >= data.grid_width || cell.y >= data.grid_height {
+        return CellRange::default();
+    }
+    cells[(cell.x + cell.y * data.grid_width) as usize]
+}
+
+//the ring iteration of neighbours.wgsl
+fn search_rings(range: f32, grid_size: i32) -> i32 {
+    ((range / grid_size as f32).ceil() as i32).max(1)
+}
+
+fn ring_cell_count(rings: i32) -> i32 {
+    let side = 2 * rings + 1;
+    side * side
+}
+
+fn ring_cell(center: IVec2, rings: i32, j: i32) -> IVec2 {
+    let side = 2 * rings + 1;
+    center + IVec2::new(j % side - rings, j / side - rings)
+}
@@ -1 +1,2 @@
+pub mod result_panel;
 pub mod stats;
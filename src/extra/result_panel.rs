@@ -0,0 +1,66 @@
+use bevy::prelude::*;
+
+use crate::battle::{BattleEnded, BattleResult};
+use crate::config::SimulationConfig;
+
+pub struct ResultPanelPlugin;
+impl Plugin for ResultPanelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (show_result, hide_result));
+    }
+}
+
+#[derive(Component)]
+struct ResultPanel;
+
+fn show_result(
+    mut commands: Commands,
+    config: Res<SimulationConfig>,
+    mut events: EventReader<BattleEnded>,
+) {
+    for BattleEnded(result) in events.read() {
+        commands
+            .spawn((
+                Node {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ZIndex(i32::MAX),
+                ResultPanel,
+            ))
+            .with_children(|parent| {
+                parent
+                    .spawn((
+                        Node {
+                            padding: UiRect::all(Val::Px(16.0)),
+                            ..default()
+                        },
+                        BackgroundColor(Color::BLACK.with_alpha(0.75)),
+                    ))
+                    .with_child((
+                        Text::new(result.summary(&config)),
+                        TextFont {
+                            font_size: 24.0,
+                            ..default()
+                        },
+                    ));
+            });
+    }
+}
+
+//loading a snapshot from before the end takes the result back
+fn hide_result(
+    mut commands: Commands,
+    result: Option<Res<BattleResult>>,
+    panels: Query<Entity, With<ResultPanel>>,
+) {
+    if result.is_none() {
+        for panel in &panels {
+            commands.entity(panel).despawn_recursive();
+        }
+    }
+}
@@ -4,13 +4,26 @@ use bevy::{
     prelude::*,
 };
 
-use crate::COUNT;
+use crate::config::SimulationConfig;
+use crate::faction::faction_sizes;
+use crate::readback::FactionReadback;
+use crate::timestep::{clock::SimulationClock, control::SimulationControl};
+use crate::SimulationUniforms;
 pub struct StatsPlugin;
 impl Plugin for StatsPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(FrameTimeDiagnosticsPlugin)
             .add_systems(Startup, setup)
-            .add_systems(Update, (update_fps_text, update_units_text));
+            .add_systems(
+                Update,
+                (
+                    update_fps_text,
+                    update_units_text,
+                    update_seed_text,
+                    update_control_text,
+                    update_faction_text,
+                ),
+            );
     }
 }
 // A unit struct to help identify the FPS UI component, since there may be many Text components
@@ -19,7 +32,17 @@ struct FpsText;
 
 #[derive(Component)]
 struct UnitsText;
-fn setup(mut commands: Commands) {
+
+#[derive(Component)]
+struct SeedText;
+
+#[derive(Component)]
+struct ControlText;
+
+//index of the faction the span shows the counters of
+#[derive(Component)]
+struct FactionText(usize);
+fn setup(mut commands: Commands, config: Res<SimulationConfig>) {
     commands
         .spawn((
             Node {
@@ -76,13 +99,136 @@ fn setup(mut commands: Commands) {
                     TextColor(GOLD.into()),
                     UnitsText,
                 ));
+            parent
+                .spawn((
+                    Text::new("Seed: "),
+                    TextFont {
+                        font_size: 14.0,
+                        ..default()
+                    },
+                ))
+                .with_child((
+                    TextSpan::default(),
+                    TextFont {
+                        font_size: 14.0,
+                        ..default()
+                    },
+                    TextColor(GOLD.into()),
+                    SeedText,
+                ));
+            parent
+                .spawn((
+                    Text::new("Simulation: "),
+                    TextFont {
+                        font_size: 14.0,
+                        ..default()
+                    },
+                ))
+                .with_child((
+                    TextSpan::default(),
+                    TextFont {
+                        font_size: 14.0,
+                        ..default()
+                    },
+                    TextColor(GOLD.into()),
+                    ControlText,
+                ));
+            for (index, faction) in config.factions.iter().enumerate() {
+                let color = TextColor(Color::srgb(
+                    faction.color.0,
+                    faction.color.1,
+                    faction.color.2,
+                ));
+                parent
+                    .spawn((
+                        Text::new(format!("{}: ", faction.name)),
+                        TextFont {
+                            font_size: 14.0,
+                            ..default()
+                        },
+                        color,
+                    ))
+                    .with_child((
+                        TextSpan::default(),
+                        TextFont {
+                            font_size: 14.0,
+                            ..default()
+                        },
+                        color,
+                        FactionText(index),
+                    ));
+            }
         });
 }
 
-fn update_units_text(mut query: Query<&mut TextSpan, With<UnitsText>>) {
+fn update_units_text(
+    config: Res<SimulationConfig>,
+    mut query: Query<&mut TextSpan, With<UnitsText>>,
+) {
     for mut span in &mut query {
-        **span = COUNT.to_string();
+        **span = config.count().to_string();
+    }
+}
+fn update_seed_text(
+    config: Res<SimulationConfig>,
+    mut query: Query<&mut TextSpan, With<SeedText>>,
+) {
+    for mut span in &mut query {
+        **span = config.seed().to_string();
+    }
+}
+fn update_control_text(
+    control: Res<SimulationControl>,
+    clock: Res<SimulationClock>,
+    mut query: Query<&mut TextSpan, With<ControlText>>,
+) {
+    for mut span in &mut query {
+        let state = if control.is_paused() {
+            "paused"
+        } else {
+            "running"
+        };
+        **span = format!(
+            "{state} at {}x, tick {} ({:.1}s)",
+            control.speed(),
+            clock.tick(),
+            clock.time()
+        );
+    }
+}
+//losses are measured against the spawned units, kill rates against the previous readback,
+//which can be from a later tick after a quickload
+fn update_faction_text(
+    config: Res<SimulationConfig>,
+    uniforms: Res<SimulationUniforms>,
+    readback: Res<FactionReadback>,
+    mut spawned: Local<Vec<u32>>,
+    mut previous: Local<Option<(u64, Vec<u32>)>>,
+    mut query: Query<(&mut TextSpan, &FactionText)>,
+) {
+    if spawned.is_empty() {
+        *spawned = faction_sizes(&uniforms.units, config.factions.len());
+    }
+    if !readback.is_changed() || readback.counts.is_empty() {
+        return;
+    }
+    let kills: Vec<u32> = readback.counts.iter().map(|counts| counts.kills).collect();
+    let seconds = match &*previous {
+        Some((tick, _)) => readback.tick.saturating_sub(*tick) as f32 / config.tick_rate,
+        None => readback.tick as f32 / config.tick_rate,
+    };
+    for (mut span, FactionText(index)) in &mut query {
+        let counts = readback.counts[*index];
+        let previous_kills = previous.as_ref().map_or(0, |(_, kills)| kills[*index]);
+        let kill_rate =
+            counts.kills.saturating_sub(previous_kills) as f32 / seconds.max(f32::EPSILON);
+        **span = format!(
+            "{} alive, {} lost, {kill_rate:.1} kills/s",
+            counts.alive,
+            spawned[*index].saturating_sub(counts.alive)
+        );
     }
+    *previous = Some((readback.tick, kills));
 }
 fn update_fps_text(
     diagnostics: Res<DiagnosticsStore>,
@@ -0,0 +1,53 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+use crate::config::SimulationConfig;
+use crate::unit::Unit;
+
+#[derive(ShaderType, Default, Clone, Copy)]
+pub struct Faction {
+    pub color: Vec4,
+    //direction the faction marches in while it has no target
+    pub advance: Vec2,
+}
+
+/// Per faction totals, `alive` is recounted by the `count_factions` pass and `kills` accumulates over the battle.
+#[derive(ShaderType, Default, Clone, Copy, Debug)]
+pub struct FactionCounts {
+    pub alive: u32,
+    pub kills: u32,
+}
+
+pub fn faction_table(config: &SimulationConfig) -> Vec<Faction> {
+    config
+        .factions
+        .iter()
+        .map(|faction| Faction {
+            color: Vec4::new(faction.color.0, faction.color.1, faction.color.2, 1.0),
+            advance: Vec2::new(faction.advance.0, faction.advance.1).normalize_or_zero(),
+        })
+        .collect()
+}
+
+/// Row-major `faction_count * faction_count` matrix, 1 where the row faction attacks the column faction.
+pub fn hostility_matrix(config: &SimulationConfig) -> Vec<u32> {
+    let count = config.factions.len();
+    let mut matrix = vec![1; count * count];
+    for faction in 0..count {
+        matrix[faction * count + faction] = 0;
+    }
+    for &(a, b) in &config.alliances {
+        let (a, b) = (a as usize, b as usize);
+        matrix[a * count + b] = 0;
+        matrix[b * count + a] = 0;
+    }
+    matrix
+}
+
+/// Units spawned per faction, sentinel padding is skipped.
+pub fn faction_sizes(units: &[Unit], faction_count: usize) -> Vec<u32> {
+    let mut sizes = vec![0; faction_count];
+    for unit in units.iter().filter(|unit| unit.id >= 0) {
+        sizes[unit.faction as usize] += 1;
+    }
+    sizes
+}
@@ -0,0 +1,8 @@
+use bevy::render::render_resource::ShaderType;
+
+/// Units `start..end` of the sorted unit buffer lie in the cell, rebuilt every tick.
+#[derive(ShaderType, Default, Clone, Copy)]
+pub struct CellRange {
+    pub start: u32,
+    pub end: u32,
+}
@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+
+use crate::battle::BattleEnded;
+use crate::config::SimulationConfig;
+
+/// Batch runs without a window: every frame runs `max_ticks_per_frame` ticks regardless of the wall clock,
+/// and the app exits once the battle is over, which includes reaching `victory.time_limit`.
+/// A time limit is required, so every run ends. The exit status is 0 whatever the outcome,
+/// only the printed summary tells who won.
+pub struct HeadlessPlugin;
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        let config = app.world().resource::<SimulationConfig>();
+        let frame_time = config.max_ticks_per_frame as f64 / config.tick_rate as f64;
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            frame_time,
+        )))
+        .add_systems(Update, exit_on_battle_end);
+    }
+}
+
+//the result itself is printed by the battle plugin when the battle ends
+fn exit_on_battle_end(mut events: EventReader<BattleEnded>, mut writer: EventWriter<AppExit>) {
+    if events.read().next().is_some() {
+        writer.send(AppExit::Success);
+    }
+}
@@ -0,0 +1,117 @@
+//! Little endian reading and writing shared by the snapshot and replay files.
+
+/// Returned when a file ends in the middle of a value.
+#[derive(Debug)]
+pub struct EndOfFile;
+
+/// Reads values from the front of a byte slice.
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Bytes left to read.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], EndOfFile> {
+        if self.bytes.len() < len {
+            return Err(EndOfFile);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, EndOfFile> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, EndOfFile> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, EndOfFile> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, EndOfFile> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, EndOfFile> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A zigzag encoded LEB128 varint, small values of either sign take a single byte.
+    pub fn varint(&mut self) -> Result<i64, EndOfFile> {
+        let mut value = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+}
+
+/// Appends a value in the encoding `ByteReader::varint` reads.
+pub fn write_varint(bytes: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: [i64; 10] = [0, 1, -1, 63, -64, 64, -65, 300_000, i64::MAX, i64::MIN];
+
+    #[test]
+    fn varints_round_trip() {
+        let mut bytes = Vec::new();
+        for value in VALUES {
+            write_varint(&mut bytes, value);
+        }
+        let mut reader = ByteReader::new(&bytes);
+        for value in VALUES {
+            assert_eq!(reader.varint().unwrap(), value);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn small_varints_take_a_byte() {
+        for value in -64..64 {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            assert_eq!(bytes.len(), 1, "{value} took {} bytes", bytes.len());
+        }
+    }
+
+    #[test]
+    fn truncated_varints_are_rejected() {
+        for value in [64, -300_000, i64::MAX, i64::MIN] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            for length in 0..bytes.len() {
+                assert!(ByteReader::new(&bytes[..length]).varint().is_err());
+            }
+        }
+    }
+}
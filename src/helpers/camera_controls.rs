@@ -7,14 +7,17 @@ use crate::SimulationUniforms;
 
 const SCROLL_SPEED: f32 = 0.1;
 const MOVE_SPEED: f32 = 500.0;
+//faction and morale colouring
+const COLOR_MODES: i32 = 2;
 pub struct CameraControlsPlugin;
 impl Plugin for CameraControlsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, update_camera);
+        app.add_systems(Update, (update_camera, update_color_mode));
     }
 }
+//real time, so the camera keeps moving at the same pace while the simulation is paused or sped up
 fn update_camera(
-    time: Res<Time>,
+    time: Res<Time<Real>>,
     keys: Res<ButtonInput<KeyCode>>,
     mut evr_scroll: EventReader<MouseWheel>,
     mut uniform_data: ResMut<SimulationUniforms>,
@@ -50,3 +53,14 @@ fn update_camera(
         }
     }
 }
+
+fn update_color_mode(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut uniform_data: ResMut<SimulationUniforms>,
+) {
+    if keys.just_pressed(KeyCode::KeyM) {
+        if let Some(data) = uniform_data.data.as_mut() {
+            data.color_mode = (data.color_mode + 1) % COLOR_MODES;
+        }
+    }
+}
@@ -1,13 +1,27 @@
-use bevy::render::render_resource::{CachedComputePipelineId, CachedPipelineState, PipelineCache};
+use bevy::render::{
+    render_resource::{
+        encase::{self, internal::WriteInto},
+        Buffer, BufferInitDescriptor, BufferUsages, CachedComputePipelineId, CachedPipelineState,
+        PipelineCache, PipelineCacheError, ShaderType,
+    },
+    renderer::{RenderDevice, RenderQueue},
+};
 
 pub fn get_pipeline_states(
     pipelines: Vec<CachedComputePipelineId>,
     cache: &PipelineCache,
     shader_path: String,
 ) -> bool {
-    for i in 0..pipelines.len() {
-        match cache.get_compute_pipeline_state(pipelines[i]) {
+    for pipeline in pipelines {
+        match cache.get_compute_pipeline_state(pipeline) {
             CachedPipelineState::Ok(_) => {}
+            //the cache retries these once the shader or its imports have loaded
+            CachedPipelineState::Err(
+                PipelineCacheError::ShaderNotLoaded(_)
+                | PipelineCacheError::ShaderImportNotYetAvailable,
+            ) => {
+                return false;
+            }
             CachedPipelineState::Err(err) => {
                 panic!("Initializing assets/{shader_path}:\n{err}")
             }
@@ -16,5 +30,34 @@ pub fn get_pipeline_states(
             }
         }
     }
-    return true;
+    true
+}
+
+/// Encodes `value` in its shader layout and writes it to the start of `buffer`.
+pub fn write_buffer<T: ShaderType + WriteInto + ?Sized>(
+    render_queue: &RenderQueue,
+    buffer: &Buffer,
+    value: &T,
+) {
+    let mut byte_buffer = Vec::new();
+    let mut storage = encase::StorageBuffer::new(&mut byte_buffer);
+    storage.write(value).unwrap();
+    render_queue.write_buffer(buffer, 0, &byte_buffer);
+}
+
+/// Creates a buffer holding `value` encoded in its shader layout.
+pub fn storage_buffer<T: ShaderType + WriteInto + ?Sized>(
+    render_device: &RenderDevice,
+    label: &str,
+    value: &T,
+    usage: BufferUsages,
+) -> Buffer {
+    let mut byte_buffer = Vec::new();
+    let mut storage = encase::StorageBuffer::new(&mut byte_buffer);
+    storage.write(value).unwrap();
+    render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some(label),
+        usage,
+        contents: &byte_buffer,
+    })
 }
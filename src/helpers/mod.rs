@@ -1,2 +1,4 @@
+pub mod bytes;
 pub mod camera_controls;
+#[allow(clippy::module_inception)]
 pub mod helpers;
@@ -0,0 +1,573 @@
+//! A large scale battle simulator that runs on GPU compute shaders inside of Bevy.
+//!
+//! Every tick the units are hashed into a spatial grid, sorted by cell and then moved, fight and take
+//! damage in a chain of compute passes, see `logic`. `cpu_sim` is a CPU port of the same passes that
+//! serves as a fallback backend and as the reference the GPU results are tested against.
+
+use battle::BattlePlugin;
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_asset::RenderAssetUsages,
+        render_graph::{RenderGraph, RenderLabel},
+        render_resource::*,
+        renderer::RenderDevice,
+        Render, RenderApp, RenderSet,
+    },
+};
+use config::{SimulationBackend, SimulationConfig, SortBackend, WindowMode};
+use cpu_sim::backend::CpuSimulationPlugin;
+use faction::{faction_table, hostility_matrix, FactionCounts};
+use grid::CellRange;
+use helpers::helpers::storage_buffer;
+use logic::{LogicNode, LogicPipeline};
+use projectile::Projectile;
+use readback::ReadbackPlugin;
+use rendering::{RenderNode, RenderingPipeline};
+use replay::{Replay, ReplayPlugin};
+use scenario::Scenario;
+use snapshot::SnapshotPlugin;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use timestep::{clock::SimulationClockPlugin, control::SimulationControlPlugin};
+use unit::{LiveUnits, Unit, UnitCombat};
+use unit_type::unit_type_table;
+
+pub mod battle;
+pub mod cli;
+pub mod config;
+pub mod cpu_sim;
+pub mod extra;
+pub mod faction;
+pub mod grid;
+pub mod headless;
+pub mod helpers;
+pub mod logic;
+pub mod projectile;
+pub mod readback;
+pub mod rendering;
+pub mod replay;
+pub mod scenario;
+pub mod snapshot;
+pub mod timestep;
+pub mod unit;
+pub mod unit_type;
+
+/// Spawns the battle and runs it on the configured backend, shared by the binary and the tests.
+///
+/// Expects the `SimulationConfig` (and the `Scenario`, `Replay` or `ReplayRecorder`, if any) to be
+/// inserted before it is added. A `Replay` is played back instead of simulating.
+pub struct SimulationPlugin;
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            SimulationComputePlugin,
+            SimulationControlPlugin,
+            SimulationClockPlugin,
+            ReadbackPlugin,
+            BattlePlugin,
+            ReplayPlugin,
+        ))
+        .add_systems(Startup, setup);
+        if app.world().contains_resource::<Replay>() {
+            return;
+        }
+        app.add_plugins(SnapshotPlugin);
+        if app.world().resource::<SimulationConfig>().backend == SimulationBackend::Cpu {
+            app.add_plugins(CpuSimulationPlugin);
+        }
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    config: Res<SimulationConfig>,
+    scenario: Option<Res<Scenario>>,
+) {
+    let size = config.size();
+    //headless runs never draw, so they get no render texture, sprite or camera
+    let image = if config.window_mode == WindowMode::Headless {
+        Handle::default()
+    } else {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: size.0,
+                height: size.1,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0, 0, 0, 255],
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::RENDER_WORLD,
+        );
+        image.texture_descriptor.usage = TextureUsages::COPY_DST
+            | TextureUsages::STORAGE_BINDING
+            | TextureUsages::TEXTURE_BINDING;
+        let image = images.add(image);
+
+        commands.spawn((
+            Sprite {
+                image: image.clone(),
+                custom_size: Some(Vec2::new(size.0 as f32, size.1 as f32)),
+                ..default()
+            },
+            Transform::from_scale(Vec3::splat(config.display_factor as f32)),
+        ));
+        commands.spawn(Camera2d);
+        image
+    };
+
+    let mut rand = StdRng::seed_from_u64(config.seed());
+    let mut units = match scenario {
+        Some(scenario) => scenario.spawn(&config, &mut rand),
+        None => default_units(&config, &mut rand),
+    };
+    units.resize(config.capacity() as usize, Unit::sentinel());
+    let uniform_data = UniformData::new(&config);
+
+    commands.insert_resource(SimulationUniforms {
+        render_texture: image,
+        units,
+        data: Some(uniform_data),
+    });
+}
+//spreads the factions over equal vertical strips of the world, separated by a small gap
+fn default_units(config: &SimulationConfig, rand: &mut impl Rng) -> Vec<Unit> {
+    let count = config.count();
+    let world_size = config.world_size;
+    let faction_count = config.factions.len() as i32;
+    let mut units = Vec::new();
+    let strip_width = world_size.0 as f32 * 0.47 * 2.0 / faction_count as f32;
+    for i in 0..count {
+        let faction = i * faction_count / count;
+        let left = -(world_size.0 as f32 * 0.47) + faction as f32 * strip_width;
+        let right = left + strip_width;
+        let left = if faction > 0 { left + 20.0 } else { left };
+        let right = if faction < faction_count - 1 {
+            right - 20.0
+        } else {
+            right
+        };
+        let position = Vec2::new(
+            rand.gen_range(left..right),
+            rand.gen_range(-((world_size.1 / 2) as f32)..((world_size.1 / 2) as f32)) * 0.47 * 2.0,
+        );
+        units.push(Unit {
+            hash_id: -1,
+            attack_id: -1,
+            previous_state: position,
+            current_state: position,
+            velocity: Vec2::ZERO,
+            id: i,
+            health: config.unit_types[0].max_health,
+            faction,
+            unit_type: 0,
+            reload: 0,
+            morale: 1.0,
+            routing: 0,
+        });
+    }
+    units
+}
+
+#[derive(Resource, Default, Deref)]
+pub struct UnitBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct SimulationUniformBuffer(Vec<Buffer>);
+//the uniforms once per bitonic sort pass, each with its own level and step, picked with a dynamic offset
+#[derive(Resource, Default, Deref)]
+pub struct SortUniformBuffer(Vec<Buffer>);
+
+#[derive(Resource, Default, Deref)]
+pub struct CellBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct FactionBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct AllianceBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct UnitTypeBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct ProjectileBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct ProjectileCounterBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct FactionCountsBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct CombatBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct LiveBuffer(Vec<Buffer>);
+//copy of LiveBuffer that is only used for indirect dispatches, a buffer can not be both in one pass
+#[derive(Resource, Default, Deref)]
+pub struct DispatchBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct CompactedBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct BlockSumBuffer(Vec<Buffer>);
+#[derive(Resource, Default, Deref)]
+pub struct BucketBuffer(Vec<Buffer>);
+#[derive(Clone, ShaderType)]
+pub struct UniformData {
+    pub dimensions: Vec2,
+    pub unit_count: i32,
+    //for bitonic sort
+    pub level: i32,
+    pub step: i32,
+    pub grid_size: i32,
+    pub grid_width: i32,
+    pub grid_height: i32,
+    pub camera_zoom: f32,
+    pub camera_position: Vec2,
+    pub alpha: f32,
+    pub targeting_factor: f32,
+    pub avoid_factor: f32,
+    pub kill_range: f32,
+    pub war_zone: f32,
+    pub faction_count: i32,
+    pub morale_recovery: f32,
+    pub outnumbered_penalty: f32,
+    pub ally_death_penalty: f32,
+    pub wound_penalty: f32,
+    pub rout_threshold: f32,
+    pub rally_threshold: f32,
+    //0 colours units by faction, 1 by morale
+    pub color_mode: i32,
+    pub boundary_mode: i32,
+}
+
+impl UniformData {
+    /// The uniforms a run starts with, the camera and colour mode are changed later from the main world.
+    pub fn new(config: &SimulationConfig) -> Self {
+        let size = config.size();
+        let (width, height) = config.hash_size();
+        let behaviour = &config.behaviour;
+        Self {
+            dimensions: Vec2::new(size.0 as f32, size.1 as f32),
+            unit_count: config.count(),
+            level: 1,
+            step: 1,
+            grid_size: config.grid_size,
+            grid_width: width,
+            grid_height: height,
+            camera_zoom: 0.25,
+            camera_position: Vec2::ZERO,
+            alpha: 0.0,
+            targeting_factor: behaviour.targeting_factor,
+            avoid_factor: behaviour.avoid_factor,
+            kill_range: behaviour.kill_range,
+            war_zone: behaviour.war_zone,
+            faction_count: config.factions.len() as i32,
+            morale_recovery: behaviour.morale_recovery,
+            outnumbered_penalty: behaviour.outnumbered_penalty,
+            ally_death_penalty: behaviour.ally_death_penalty,
+            wound_penalty: behaviour.wound_penalty,
+            rout_threshold: behaviour.rout_threshold,
+            rally_threshold: behaviour.rally_threshold,
+            color_mode: 0,
+            boundary_mode: config.boundary as i32,
+        }
+    }
+}
+
+fn create_buffers(
+    render_device: Res<RenderDevice>,
+    config: Res<SimulationConfig>,
+    simulation_uniforms: ResMut<SimulationUniforms>,
+    (mut unit_buffer, mut uniform_buffer, mut sort_uniform_buffer, mut cell_buffer): (
+        ResMut<UnitBuffer>,
+        ResMut<SimulationUniformBuffer>,
+        ResMut<SortUniformBuffer>,
+        ResMut<CellBuffer>,
+    ),
+    (mut faction_buffer, mut alliance_buffer, mut unit_type_buffer): (
+        ResMut<FactionBuffer>,
+        ResMut<AllianceBuffer>,
+        ResMut<UnitTypeBuffer>,
+    ),
+    (mut projectile_buffer, mut projectile_counter_buffer, mut faction_counts_buffer): (
+        ResMut<ProjectileBuffer>,
+        ResMut<ProjectileCounterBuffer>,
+        ResMut<FactionCountsBuffer>,
+    ),
+) {
+    if unit_buffer.0.is_empty() {
+        let usage = BufferUsages::COPY_DST | BufferUsages::STORAGE | BufferUsages::COPY_SRC;
+        unit_buffer.0.push(storage_buffer(
+            &render_device,
+            "units",
+            &simulation_uniforms.units,
+            usage,
+        ));
+
+        uniform_buffer.0.push(storage_buffer(
+            &render_device,
+            "simulation uniforms",
+            &simulation_uniforms.data.clone().unwrap(),
+            BufferUsages::COPY_DST | BufferUsages::UNIFORM | BufferUsages::COPY_SRC,
+        ));
+
+        if config.sort_backend == SortBackend::Bitonic {
+            let alignment = render_device.limits().min_uniform_buffer_offset_alignment;
+            let mut byte_buffer = Vec::new();
+            let mut buffer = encase::DynamicUniformBuffer::new_with_alignment(
+                &mut byte_buffer,
+                alignment as u64,
+            );
+            let mut uniform_data = simulation_uniforms.data.clone().unwrap();
+            for (level, step) in logic::bitonic_passes(config.capacity() as u32) {
+                uniform_data.level = level;
+                uniform_data.step = step;
+                buffer.write(&uniform_data).unwrap();
+            }
+
+            //spaced out for dynamic offsets, which the storage layout of `storage_buffer` cannot do
+            let uniform = render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("sort uniforms"),
+                usage: BufferUsages::COPY_DST | BufferUsages::UNIFORM | BufferUsages::COPY_SRC,
+                contents: buffer.into_inner(),
+            });
+            sort_uniform_buffer.0.push(uniform);
+        }
+
+        cell_buffer.0.push(storage_buffer(
+            &render_device,
+            "cells",
+            &vec![CellRange::default(); config.cell_count() as usize],
+            usage,
+        ));
+
+        faction_buffer.0.push(storage_buffer(
+            &render_device,
+            "factions",
+            &faction_table(&config),
+            usage,
+        ));
+
+        alliance_buffer.0.push(storage_buffer(
+            &render_device,
+            "alliances",
+            &hostility_matrix(&config),
+            usage,
+        ));
+
+        unit_type_buffer.0.push(storage_buffer(
+            &render_device,
+            "unit types",
+            &unit_type_table(&config),
+            usage,
+        ));
+
+        projectile_buffer.0.push(storage_buffer(
+            &render_device,
+            "projectiles",
+            &vec![Projectile::default(); config.max_projectiles as usize],
+            usage,
+        ));
+
+        projectile_counter_buffer.0.push(storage_buffer(
+            &render_device,
+            "projectile counter",
+            &0_u32,
+            usage,
+        ));
+
+        faction_counts_buffer.0.push(storage_buffer(
+            &render_device,
+            "faction counts",
+            &vec![FactionCounts::default(); config.factions.len()],
+            usage,
+        ));
+    }
+}
+
+//buffers indexed by unit slot that the compaction and the sorts work with
+fn create_unit_slot_buffers(
+    render_device: Res<RenderDevice>,
+    config: Res<SimulationConfig>,
+    simulation_uniforms: Res<SimulationUniforms>,
+    (mut combat_buffer, mut live_buffer, mut dispatch_buffer): (
+        ResMut<CombatBuffer>,
+        ResMut<LiveBuffer>,
+        ResMut<DispatchBuffer>,
+    ),
+    (mut compacted_buffer, mut block_sum_buffer, mut bucket_buffer): (
+        ResMut<CompactedBuffer>,
+        ResMut<BlockSumBuffer>,
+        ResMut<BucketBuffer>,
+    ),
+) {
+    if combat_buffer.0.is_empty() {
+        let usage = BufferUsages::COPY_DST | BufferUsages::STORAGE | BufferUsages::COPY_SRC;
+        combat_buffer.0.push(storage_buffer(
+            &render_device,
+            "unit combat",
+            &vec![UnitCombat::default(); config.capacity() as usize],
+            usage,
+        ));
+
+        let live = LiveUnits::new(
+            config.count() as u32,
+            config.capacity() as u32,
+            config.workgroup_size,
+        );
+        live_buffer
+            .0
+            .push(storage_buffer(&render_device, "live units", &live, usage));
+        dispatch_buffer.0.push(storage_buffer(
+            &render_device,
+            "dispatch",
+            &live,
+            BufferUsages::COPY_DST | BufferUsages::INDIRECT,
+        ));
+
+        //starts out as a copy of the units so everything past the live count is already padding
+        compacted_buffer.0.push(storage_buffer(
+            &render_device,
+            "compacted units",
+            &simulation_uniforms.units,
+            usage,
+        ));
+
+        //shared by the compaction scan and the counting sort scan
+        let blocks = (config.capacity() as u32)
+            .max(config.bucket_count())
+            .div_ceil(config.workgroup_size);
+        block_sum_buffer.0.push(storage_buffer(
+            &render_device,
+            "block sums",
+            &vec![0_u32; blocks as usize],
+            usage,
+        ));
+
+        bucket_buffer.0.push(storage_buffer(
+            &render_device,
+            "buckets",
+            &vec![0_u32; config.bucket_count() as usize],
+            usage,
+        ));
+    }
+}
+
+pub struct SimulationComputePlugin;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct LogicLabel;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct RenderingLabel;
+
+impl Plugin for SimulationComputePlugin {
+    fn build(&self, app: &mut App) {
+        let render = draws_frames(app.world().resource::<SimulationConfig>());
+        let logic = runs_logic(app.world());
+        app.add_plugins(ExtractResourcePlugin::<SimulationUniforms>::default());
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.add_systems(
+            Render,
+            (create_buffers, create_unit_slot_buffers).in_set(RenderSet::PrepareBindGroups),
+        );
+        if logic {
+            render_app
+                .add_systems(
+                    Render,
+                    logic::prepare_bind_group
+                        .after(create_buffers)
+                        .after(create_unit_slot_buffers)
+                        .in_set(RenderSet::PrepareBindGroups),
+                )
+                .add_systems(ExtractSchedule, logic::publish_ready);
+        }
+        if render {
+            render_app.add_systems(
+                Render,
+                rendering::prepare_bind_group
+                    .after(create_buffers)
+                    .after(create_unit_slot_buffers)
+                    .in_set(RenderSet::PrepareBindGroups),
+            );
+        }
+        render_app.init_resource::<UnitBuffer>();
+        render_app.init_resource::<SimulationUniformBuffer>();
+        render_app.init_resource::<SortUniformBuffer>();
+        render_app.init_resource::<CellBuffer>();
+        render_app.init_resource::<FactionBuffer>();
+        render_app.init_resource::<AllianceBuffer>();
+        render_app.init_resource::<UnitTypeBuffer>();
+        render_app.init_resource::<ProjectileBuffer>();
+        render_app.init_resource::<ProjectileCounterBuffer>();
+        render_app.init_resource::<FactionCountsBuffer>();
+        render_app.init_resource::<CombatBuffer>();
+        render_app.init_resource::<LiveBuffer>();
+        render_app.init_resource::<DispatchBuffer>();
+        render_app.init_resource::<CompactedBuffer>();
+        render_app.init_resource::<BlockSumBuffer>();
+        render_app.init_resource::<BucketBuffer>();
+
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+
+        if logic {
+            render_graph.add_node(LogicLabel, LogicNode::default());
+        }
+        if render {
+            render_graph.add_node(RenderingLabel, RenderNode::default());
+
+            if logic {
+                render_graph.add_node_edge(LogicLabel, RenderingLabel);
+            }
+            render_graph.add_node_edge(RenderingLabel, bevy::render::graph::CameraDriverLabel);
+        }
+    }
+
+    fn finish(&self, app: &mut App) {
+        let config = app.world().resource::<SimulationConfig>().clone();
+        let render = draws_frames(&config);
+        let logic = runs_logic(app.world());
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.insert_resource(config);
+        if logic {
+            render_app.init_resource::<LogicPipeline>();
+        }
+        if render {
+            render_app.init_resource::<RenderingPipeline>();
+        }
+    }
+}
+
+//headless runs only simulate, the rendering node and its pipelines are left out entirely
+fn draws_frames(config: &SimulationConfig) -> bool {
+    config.window_mode != WindowMode::Headless
+}
+
+//the CPU backend steps the units itself and replays play recorded units back, both only upload them for rendering
+fn runs_logic(world: &World) -> bool {
+    world.resource::<SimulationConfig>().backend == SimulationBackend::Gpu
+        && !world.contains_resource::<Replay>()
+}
+
+pub fn shader_defs(config: &SimulationConfig) -> Vec<ShaderDefVal> {
+    vec![ShaderDefVal::UInt(
+        "WORKGROUP_SIZE".into(),
+        config.workgroup_size,
+    )]
+}
+
+#[derive(Resource, Clone)]
+pub struct SimulationUniforms {
+    data: Option<UniformData>,
+    render_texture: Handle<Image>,
+    units: Vec<Unit>,
+}
+
+impl ExtractResource for SimulationUniforms {
+    type Source = SimulationUniforms;
+
+    fn extract_resource(uniforms: &Self::Source) -> Self {
+        SimulationUniforms {
+            data: uniforms.data.clone(),
+            render_texture: uniforms.render_texture.clone(),
+            units: uniforms.units.clone(),
+        }
+    }
+}
@@ -1,16 +1,24 @@
 use std::borrow::Cow;
 
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use bevy::render::{
     render_graph::{self},
     render_resource::*,
     renderer::{RenderContext, RenderDevice},
+    MainWorld,
 };
 
+use crate::config::{SimulationConfig, SortBackend};
 use crate::helpers::helpers::get_pipeline_states;
-use crate::timestep::fixed_time::FixedTimestep;
+use crate::readback::{copy_to_staging, ReadbackState};
+use crate::timestep::clock::SimulationClock;
+use crate::unit::LiveUnits;
 use crate::{
-    IndicesBuffer, SimulationUniformBuffer, SimulationUniforms, UnitBuffer, COUNT, WORKGROUP_SIZE,
+    shader_defs, AllianceBuffer, BlockSumBuffer, BucketBuffer, CellBuffer, CombatBuffer,
+    CompactedBuffer, DispatchBuffer, FactionBuffer, FactionCountsBuffer, LiveBuffer,
+    ProjectileBuffer, ProjectileCounterBuffer, SimulationUniformBuffer, SortUniformBuffer,
+    UniformData, UnitBuffer, UnitTypeBuffer,
 };
 const SHADER_ASSET_PATH: &str = "shaders/logic.wgsl";
 
@@ -34,57 +42,111 @@ impl Default for LogicNode {
 }
 
 #[derive(Resource)]
-pub struct LogicBindGroup(pub BindGroup);
+pub struct LogicBindGroup {
+    pub bind_group: BindGroup,
+    //bound with the offset of a bitonic sort pass, only there for the bitonic backend
+    pub sort_bind_group: Option<BindGroup>,
+}
+
+/// The level and step of every bitonic sort pass over `size` units, in the order they run.
+pub fn bitonic_passes(size: u32) -> impl Iterator<Item = (i32, i32)> {
+    (1..=size.ilog2()).flat_map(|sort_pass| {
+        let level = 2_i32.pow(sort_pass);
+        (1..=sort_pass)
+            .rev()
+            .map(move |pass_exp| (level, 2_i32.pow(pass_exp)))
+    })
+}
+
+//distance between the copies of the uniforms in the sort uniform buffer
+fn sort_uniform_stride(render_device: &RenderDevice) -> u32 {
+    let alignment = render_device.limits().min_uniform_buffer_offset_alignment;
+    (UniformData::min_size().get() as u32).next_multiple_of(alignment)
+}
 
 pub fn prepare_bind_group(
     mut commands: Commands,
     pipeline: Res<LogicPipeline>,
-    unit_buffer: Res<UnitBuffer>,
     uniform_buffer: Res<SimulationUniformBuffer>,
-    indices_buffer: Res<IndicesBuffer>,
+    sort_uniform_buffer: Res<SortUniformBuffer>,
+    buffers: LogicBuffers,
     render_device: Res<RenderDevice>,
-    time: Res<Time>,
-    mut fixed: ResMut<FixedTimestep>,
 ) {
-    
-    //timestep code
-    while fixed.accumulater >= fixed.timestep {
-        fixed.time += fixed.timestep;
-        fixed.accumulater -= fixed.timestep;
-    }
-    let new_time = time.elapsed_secs();
-    let mut frame_time = new_time - fixed.current_time;
-    if frame_time > 0.1 {
-        frame_time = 0.1;
-    }
-
-    fixed.current_time = new_time;
-    fixed.accumulater += frame_time;
-    let mut accumulator = fixed.accumulater;
-    while accumulator >= fixed.timestep {
-        accumulator -= fixed.timestep;
-    }
-    fixed.alpha = accumulator / fixed.timestep;
-
-    let bind_group = render_device.create_bind_group(
-        None,
+    let bind_group = buffers.bind_group(
+        &render_device,
         &pipeline.texture_bind_group_layout,
-        &[
-            BindGroupEntry {
-                binding: 0,
-                resource: BindingResource::Buffer(unit_buffer.0[0].as_entire_buffer_binding()),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: BindingResource::Buffer(indices_buffer.0[0].as_entire_buffer_binding()),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: BindingResource::Buffer(uniform_buffer.0[0].as_entire_buffer_binding()),
-            },
-        ],
+        &uniform_buffer.0[0],
     );
-    commands.insert_resource(LogicBindGroup(bind_group));
+
+    let sort_bind_group = sort_uniform_buffer.first().map(|uniform| {
+        buffers.bind_group(&render_device, &pipeline.texture_bind_group_layout, uniform)
+    });
+    commands.insert_resource(LogicBindGroup {
+        bind_group,
+        sort_bind_group,
+    });
+}
+
+/// The storage buffers bound by every logic pass.
+#[derive(SystemParam)]
+pub struct LogicBuffers<'w> {
+    units: Res<'w, UnitBuffer>,
+    cells: Res<'w, CellBuffer>,
+    factions: Res<'w, FactionBuffer>,
+    alliances: Res<'w, AllianceBuffer>,
+    unit_types: Res<'w, UnitTypeBuffer>,
+    projectiles: Res<'w, ProjectileBuffer>,
+    projectile_counter: Res<'w, ProjectileCounterBuffer>,
+    combat: Res<'w, CombatBuffer>,
+    live: Res<'w, LiveBuffer>,
+    compacted: Res<'w, CompactedBuffer>,
+    block_sums: Res<'w, BlockSumBuffer>,
+    buckets: Res<'w, BucketBuffer>,
+    faction_counts: Res<'w, FactionCountsBuffer>,
+}
+
+impl LogicBuffers<'_> {
+    fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        layout: &BindGroupLayout,
+        uniform: &Buffer,
+    ) -> BindGroup {
+        //in binding order, see logic.wgsl
+        let buffers = [
+            &self.units.0[0],
+            &self.cells.0[0],
+            uniform,
+            &self.factions.0[0],
+            &self.alliances.0[0],
+            &self.unit_types.0[0],
+            &self.projectiles.0[0],
+            &self.projectile_counter.0[0],
+            &self.combat.0[0],
+            &self.live.0[0],
+            &self.compacted.0[0],
+            &self.block_sums.0[0],
+            &self.buckets.0[0],
+            &self.faction_counts.0[0],
+        ];
+        let entries: Vec<_> = buffers
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer)| BindGroupEntry {
+                binding: binding as u32,
+                resource: match binding {
+                    //the uniforms take a dynamic offset, so only one copy of them is bound
+                    2 => BindingResource::Buffer(BufferBinding {
+                        buffer,
+                        offset: 0,
+                        size: Some(UniformData::min_size()),
+                    }),
+                    _ => buffer.as_entire_binding(),
+                },
+            })
+            .collect();
+        render_device.create_bind_group(None, layout, &entries)
+    }
 }
 
 #[derive(Resource)]
@@ -92,8 +154,38 @@ pub struct LogicPipeline {
     pub texture_bind_group_layout: BindGroupLayout,
     pub sort_pipeline: CachedComputePipelineId,
     pub hash_pipeline: CachedComputePipelineId,
+    pub clear_cells_pipeline: CachedComputePipelineId,
     pub hash_indices_pipeline: CachedComputePipelineId,
     pub update_pipeline: CachedComputePipelineId,
+    pub projectile_pipeline: CachedComputePipelineId,
+    pub apply_damage_pipeline: CachedComputePipelineId,
+    pub compact_count_pipeline: CachedComputePipelineId,
+    pub compact_scan_pipeline: CachedComputePipelineId,
+    pub compact_scatter_pipeline: CachedComputePipelineId,
+    pub compact_finish_pipeline: CachedComputePipelineId,
+    pub clear_buckets_pipeline: CachedComputePipelineId,
+    pub count_buckets_pipeline: CachedComputePipelineId,
+    pub scan_buckets_pipeline: CachedComputePipelineId,
+    pub scan_bucket_blocks_pipeline: CachedComputePipelineId,
+    pub offset_buckets_pipeline: CachedComputePipelineId,
+    pub scatter_units_pipeline: CachedComputePipelineId,
+    pub rank_units_pipeline: CachedComputePipelineId,
+    pub cell_ranges_pipeline: CachedComputePipelineId,
+    pub clear_faction_counts_pipeline: CachedComputePipelineId,
+    pub count_factions_pipeline: CachedComputePipelineId,
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
 }
 
 impl FromWorld for LogicPipeline {
@@ -102,104 +194,158 @@ impl FromWorld for LogicPipeline {
         let texture_bind_group_layout = render_device.create_bind_group_layout(
             "LogicUniforms",
             &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
+                storage_entry(0, false),
+                storage_entry(1, false),
                 BindGroupLayoutEntry {
                     binding: 2,
                     visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(UniformData::min_size()),
                     },
                     count: None,
                 },
+                storage_entry(3, true),
+                storage_entry(4, true),
+                storage_entry(5, true),
+                storage_entry(6, false),
+                storage_entry(7, false),
+                storage_entry(8, false),
+                storage_entry(9, false),
+                storage_entry(10, false),
+                storage_entry(11, false),
+                storage_entry(12, false),
+                storage_entry(13, false),
             ],
         );
+        let shader_defs = shader_defs(world.resource::<SimulationConfig>());
         let shader = world.load_asset(SHADER_ASSET_PATH);
         let pipeline_cache = world.resource::<PipelineCache>();
-        let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![texture_bind_group_layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: shader.clone(),
-            shader_defs: vec![],
-            entry_point: Cow::from("update"),
-            zero_initialize_workgroup_memory: false,
-        });
-
-        let sort_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![texture_bind_group_layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: shader.clone(),
-            shader_defs: vec![],
-            entry_point: Cow::from("sort"),
-            zero_initialize_workgroup_memory: false,
-        });
-        let hash_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![texture_bind_group_layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: shader.clone(),
-            shader_defs: vec![],
-            entry_point: Cow::from("hash"),
-            zero_initialize_workgroup_memory: false,
-        });
-        let hash_indices_pipeline =
+        let queue = |entry_point: &'static str| {
             pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
                 label: None,
                 layout: vec![texture_bind_group_layout.clone()],
                 push_constant_ranges: Vec::new(),
-                shader,
-                shader_defs: vec![],
-                entry_point: Cow::from("hash_indices"),
+                shader: shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: Cow::from(entry_point),
                 zero_initialize_workgroup_memory: false,
-            });
+            })
+        };
         LogicPipeline {
+            sort_pipeline: queue("sort"),
+            hash_pipeline: queue("hash"),
+            clear_cells_pipeline: queue("clear_cells"),
+            hash_indices_pipeline: queue("hash_indices"),
+            update_pipeline: queue("update"),
+            projectile_pipeline: queue("update_projectiles"),
+            apply_damage_pipeline: queue("apply_damage"),
+            compact_count_pipeline: queue("compact_count"),
+            compact_scan_pipeline: queue("compact_scan"),
+            compact_scatter_pipeline: queue("compact_scatter"),
+            compact_finish_pipeline: queue("compact_finish"),
+            clear_buckets_pipeline: queue("clear_buckets"),
+            count_buckets_pipeline: queue("count_buckets"),
+            scan_buckets_pipeline: queue("scan_buckets"),
+            scan_bucket_blocks_pipeline: queue("scan_bucket_blocks"),
+            offset_buckets_pipeline: queue("offset_buckets"),
+            scatter_units_pipeline: queue("scatter_units"),
+            rank_units_pipeline: queue("rank_units"),
+            cell_ranges_pipeline: queue("cell_ranges"),
+            clear_faction_counts_pipeline: queue("clear_faction_counts"),
+            count_factions_pipeline: queue("count_factions"),
             texture_bind_group_layout,
-            sort_pipeline,
-            hash_pipeline,
-            hash_indices_pipeline,
-            update_pipeline,
         }
     }
 }
 
+impl LogicPipeline {
+    /// Whether every logic pipeline has finished compiling, panics if one of them failed.
+    pub fn is_ready(&self, pipeline_cache: &PipelineCache) -> bool {
+        let ids = vec![
+            self.sort_pipeline,
+            self.hash_pipeline,
+            self.clear_cells_pipeline,
+            self.hash_indices_pipeline,
+            self.update_pipeline,
+            self.projectile_pipeline,
+            self.apply_damage_pipeline,
+            self.compact_count_pipeline,
+            self.compact_scan_pipeline,
+            self.compact_scatter_pipeline,
+            self.compact_finish_pipeline,
+            self.clear_buckets_pipeline,
+            self.count_buckets_pipeline,
+            self.scan_buckets_pipeline,
+            self.scan_bucket_blocks_pipeline,
+            self.offset_buckets_pipeline,
+            self.scatter_units_pipeline,
+            self.rank_units_pipeline,
+            self.cell_ranges_pipeline,
+            self.clear_faction_counts_pipeline,
+            self.count_factions_pipeline,
+        ];
+        get_pipeline_states(ids, pipeline_cache, SHADER_ASSET_PATH.to_owned())
+    }
+}
+
+//the clock only starts counting ticks once the logic node can actually run them
+pub fn publish_ready(
+    mut main_world: ResMut<MainWorld>,
+    pipeline: Res<LogicPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+) {
+    //queued pipelines only enter the cache on the first render, their ids are not valid before that
+    if pipeline_cache.pipelines().next().is_none() {
+        return;
+    }
+    let mut clock = main_world.resource_mut::<SimulationClock>();
+    if !clock.is_started() && pipeline.is_ready(&pipeline_cache) {
+        clock.start();
+    }
+}
+
+/// The steps of a tick, each recorded as its own compute pass so they can also be run one at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogicPass {
+    Hash,
+    //the counting sort also builds the cell table
+    Sort,
+    //only needed after the bitonic sort
+    HashIndices,
+    Update,
+    Projectiles,
+    ApplyDamage,
+    Compact,
+}
+
+impl LogicPass {
+    /// The passes of one tick, in the order they run.
+    pub fn tick(config: &SimulationConfig, compact: bool) -> Vec<LogicPass> {
+        let mut passes = vec![LogicPass::Hash, LogicPass::Sort];
+        if config.sort_backend == SortBackend::Bitonic {
+            passes.push(LogicPass::HashIndices);
+        }
+        passes.extend([
+            LogicPass::Update,
+            LogicPass::Projectiles,
+            LogicPass::ApplyDamage,
+        ]);
+        if compact {
+            passes.push(LogicPass::Compact);
+        }
+        passes
+    }
+}
+
 impl render_graph::Node for LogicNode {
     fn update(&mut self, world: &mut World) {
         let pipeline = world.resource::<LogicPipeline>();
         let pipeline_cache = world.resource::<PipelineCache>();
 
-        if self.state == LogicState::Loading {
-            let ids = vec![
-                pipeline.sort_pipeline,
-                pipeline.hash_pipeline,
-                pipeline.hash_indices_pipeline,
-                pipeline.update_pipeline,
-            ];
-
-            if get_pipeline_states(ids, &pipeline_cache, SHADER_ASSET_PATH.to_owned()) {
-                self.state = LogicState::Update;
-            }
+        if self.state == LogicState::Loading && pipeline.is_ready(pipeline_cache) {
+            self.state = LogicState::Update;
         }
     }
 
@@ -209,177 +355,272 @@ impl render_graph::Node for LogicNode {
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
-        let bind_group = &world.resource::<LogicBindGroup>().0;
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<LogicPipeline>();
-        let unit_buffer = world.resource::<UnitBuffer>();
-        let indices_buffer = world.resource::<IndicesBuffer>();
-        let render_device = world.resource::<RenderDevice>();
-        let simulation_data = world.resource::<SimulationUniforms>();
-        let fixed = world.resource::<FixedTimestep>();
-        let mut accumulater = fixed.accumulater;
-        while accumulater >= fixed.timestep {
-            self.logic_update(
-                render_context,
-                bind_group,
-                pipeline_cache,
-                pipeline,
-                unit_buffer,
-                indices_buffer,
-                render_device,
-                simulation_data,
-            );
-            accumulater -= fixed.timestep;
+        let clock = world.resource::<SimulationClock>();
+        let config = world.resource::<SimulationConfig>();
+        if let LogicState::Update = self.state {
+            for tick in clock.frame_ticks() {
+                let compact = tick.is_multiple_of(config.compaction_interval as u64);
+                for pass in LogicPass::tick(config, compact) {
+                    self.run_pass(pass, render_context, world);
+                }
+            }
+            if world.resource::<ReadbackState>().factions.is_some() {
+                self.count_factions(render_context, world);
+            }
         }
+        copy_to_staging(render_context, world);
 
         Ok(())
     }
 }
 
 impl LogicNode {
-    fn logic_update(
-        &self,
-        render_context: &mut RenderContext,
-        bind_group: &BindGroup,
-        pipeline_cache: &PipelineCache,
-        pipeline: &LogicPipeline,
-        unit_buffer: &UnitBuffer,
-        indices_buffer: &IndicesBuffer,
-        render_device: &RenderDevice,
-        simulation_data: &SimulationUniforms,
-    ) {
-        // select the pipeline based on the current state
-        match self.state {
-            LogicState::Loading => {}
-            LogicState::Interpolate => {}
-            LogicState::Update => {
-                let mut pass_1 =
-                    render_context
-                        .command_encoder()
-                        .begin_compute_pass(&ComputePassDescriptor {
-                            label: Some(&"hash"),
-                            ..Default::default()
-                        });
-
-                let hash_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.hash_pipeline)
-                    .unwrap();
-                pass_1.set_bind_group(0, bind_group, &[]);
-                pass_1.set_pipeline(hash_pipeline);
-
-                pass_1.dispatch_workgroups((COUNT as u32) / WORKGROUP_SIZE, 1, 1);
-
-                drop(pass_1);
-
-                let num = COUNT.ilog(2) as i32;
-                for sort_pass in 1..=num {
-                    let level = 2_i32.pow(sort_pass as u32);
-                    for pass_exp in (1..=sort_pass).rev() {
-                        let step = 2_i32.pow(pass_exp as u32);
-
-                        let mut uniform_data = simulation_data.data.clone().unwrap();
-                        uniform_data.level = level;
-                        uniform_data.step = step;
-
-                        let mut byte_buffer = Vec::new();
-                        let mut buffer = encase::StorageBuffer::new(&mut byte_buffer);
-                        buffer.write(&uniform_data).unwrap();
-
-                        let uniform =
-                            render_device.create_buffer_with_data(&BufferInitDescriptor {
-                                label: None,
-                                usage: BufferUsages::COPY_DST
-                                    | BufferUsages::UNIFORM
-                                    | BufferUsages::COPY_SRC,
-                                contents: buffer.into_inner(),
-                            });
-
-                        let bind_group = render_device.create_bind_group(
-                            None,
-                            &pipeline.texture_bind_group_layout,
-                            &[
-                                BindGroupEntry {
-                                    binding: 0,
-                                    resource: BindingResource::Buffer(
-                                        unit_buffer.0[0].as_entire_buffer_binding(),
-                                    ),
-                                },
-                                BindGroupEntry {
-                                    binding: 1,
-                                    resource: BindingResource::Buffer(
-                                        indices_buffer.0[0].as_entire_buffer_binding(),
-                                    ),
-                                },
-                                BindGroupEntry {
-                                    binding: 2,
-                                    resource: BindingResource::Buffer(
-                                        uniform.as_entire_buffer_binding(),
-                                    ),
-                                },
-                            ],
-                        );
-
-                        let mut pass = render_context.command_encoder().begin_compute_pass(
-                            &ComputePassDescriptor {
-                                label: Some(
-                                    ("level ".to_owned()
-                                        + level.to_string().as_str()
-                                        + " step "
-                                        + step.to_string().as_str())
-                                    .as_str(),
-                                ),
-                                ..Default::default()
-                            },
-                        );
-
-                        let sort_pipeline = pipeline_cache
-                            .get_compute_pipeline(pipeline.sort_pipeline)
-                            .unwrap();
-
-                        pass.set_bind_group(0, &bind_group, &[]);
-                        pass.set_pipeline(sort_pipeline);
-
-                        pass.dispatch_workgroups((COUNT as u32) / (2 * WORKGROUP_SIZE), 1, 1);
-
-                        drop(pass);
-                    }
+    /// Records a single pass of a tick, the pipelines have to be compiled already.
+    pub fn run_pass(&self, pass: LogicPass, render_context: &mut RenderContext, world: &World) {
+        let bind_group = &world.resource::<LogicBindGroup>().bind_group;
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline = world.resource::<LogicPipeline>();
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+        let config = world.resource::<SimulationConfig>();
+        let (label, id) = match pass {
+            LogicPass::Hash => ("hash", pipeline.hash_pipeline),
+            LogicPass::Update => ("update", pipeline.update_pipeline),
+            LogicPass::Projectiles => ("projectiles", pipeline.projectile_pipeline),
+            //damage and target claims gathered by update and the projectiles are resolved in one go
+            LogicPass::ApplyDamage => ("apply damage", pipeline.apply_damage_pipeline),
+            LogicPass::Sort => {
+                match config.sort_backend {
+                    SortBackend::Bitonic => self.bitonic_sort(render_context, world),
+                    SortBackend::Counting => self.counting_sort(render_context, world),
+                }
+                return;
+            }
+            LogicPass::HashIndices => {
+                self.hash_indices(render_context, world);
+                return;
+            }
+            LogicPass::Compact => {
+                self.compact_units(render_context, world);
+                return;
+            }
+        };
+
+        let mut pass_encoder =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some(label),
+                    ..Default::default()
+                });
+        pass_encoder.set_bind_group(0, bind_group, &[0]);
+        pass_encoder.set_pipeline(pipeline_cache.get_compute_pipeline(id).unwrap());
+        if pass == LogicPass::Projectiles {
+            let projectile_workgroups = config.max_projectiles.div_ceil(config.workgroup_size);
+            pass_encoder.dispatch_workgroups(projectile_workgroups, 1, 1);
+        } else {
+            pass_encoder.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::DISPATCH_OFFSET);
+        }
+    }
+
+    //compacts and makes the result the unit buffer for the next tick
+    fn compact_units(&self, render_context: &mut RenderContext, world: &World) {
+        let unit_buffer = &world.resource::<UnitBuffer>().0[0];
+        let live_buffer = &world.resource::<LiveBuffer>().0[0];
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+        let compacted_buffer = &world.resource::<CompactedBuffer>().0[0];
+
+        self.compact(render_context, world);
+        //compact_scatter filled the compacted buffer, everything past the live count is padding
+        let encoder = render_context.command_encoder();
+        encoder.copy_buffer_to_buffer(compacted_buffer, 0, unit_buffer, 0, unit_buffer.size());
+        encoder.copy_buffer_to_buffer(live_buffer, 0, dispatch_buffer, 0, live_buffer.size());
+    }
+
+    /// Orders the units by hash id with a bitonic sorting network, `hash_indices` then finds the cells.
+    fn bitonic_sort(&self, render_context: &mut RenderContext, world: &World) {
+        let config = world.resource::<SimulationConfig>();
+        let logic_bind_group = world.resource::<LogicBindGroup>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline = world.resource::<LogicPipeline>();
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+
+        let sort_bind_group = logic_bind_group.sort_bind_group.as_ref().unwrap();
+        let stride = sort_uniform_stride(world.resource::<RenderDevice>());
+        //the live units only ever shrink, so the last live count read back bounds the power of two to sort
+        let live_count = world
+            .get_resource::<ReadbackState>()
+            .and_then(ReadbackState::live_count)
+            .unwrap_or(config.count() as u32);
+        let size = live_count
+            .next_power_of_two()
+            .max(2 * config.workgroup_size);
+        let passes = bitonic_passes(size).count();
+
+        let sort_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.sort_pipeline)
+            .unwrap();
+        for sort_pass in 0..passes {
+            let mut pass =
+                render_context
+                    .command_encoder()
+                    .begin_compute_pass(&ComputePassDescriptor {
+                        label: Some(format!("sort pass {sort_pass}").as_str()),
+                        ..Default::default()
+                    });
+
+            pass.set_bind_group(0, sort_bind_group, &[sort_pass as u32 * stride]);
+            pass.set_pipeline(sort_pipeline);
+
+            //only covers the power of two holding the live units, the rest is padding
+            pass.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::SORT_OFFSET);
+        }
+    }
+
+    /// Finds where each cell starts and ends in the sorted units.
+    fn hash_indices(&self, render_context: &mut RenderContext, world: &World) {
+        let config = world.resource::<SimulationConfig>();
+        let cell_workgroups = config.cell_count().div_ceil(config.workgroup_size);
+        let bind_group = &world.resource::<LogicBindGroup>().bind_group;
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline = world.resource::<LogicPipeline>();
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("hash indices"),
+                    ..Default::default()
+                });
+
+        pass.set_bind_group(0, bind_group, &[0]);
+
+        //every cell is reset so cells that emptied out since the last tick do not keep stale ranges
+        let clear_cells_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.clear_cells_pipeline)
+            .unwrap();
+        pass.set_pipeline(clear_cells_pipeline);
+        pass.dispatch_workgroups(cell_workgroups, 1, 1);
+
+        let hash_id_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.hash_indices_pipeline)
+            .unwrap();
+        pass.set_pipeline(hash_id_pipeline);
+
+        pass.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::DISPATCH_OFFSET);
+    }
+
+    /// Orders the units by cell with a histogram, a prefix sum and a scatter into the compacted buffer,
+    /// then ranks them by id inside their cell on the way back into the unit buffer.
+    /// The bucket offsets are the cell ranges, so no separate hash indices pass is needed.
+    fn counting_sort(&self, render_context: &mut RenderContext, world: &World) {
+        let bind_group = &world.resource::<LogicBindGroup>().bind_group;
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline = world.resource::<LogicPipeline>();
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+        let config = world.resource::<SimulationConfig>();
+        let bucket_workgroups = config.bucket_count().div_ceil(config.workgroup_size);
+        let cell_workgroups = config.cell_count().div_ceil(config.workgroup_size);
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("counting sort"),
+                    ..Default::default()
+                });
+        pass.set_bind_group(0, bind_group, &[0]);
+
+        let passes = [
+            (pipeline.clear_buckets_pipeline, Some(bucket_workgroups)),
+            (pipeline.count_buckets_pipeline, None),
+            (pipeline.scan_buckets_pipeline, Some(bucket_workgroups)),
+            (pipeline.scan_bucket_blocks_pipeline, Some(1)),
+            (pipeline.offset_buckets_pipeline, Some(bucket_workgroups)),
+            (pipeline.scatter_units_pipeline, None),
+            (pipeline.rank_units_pipeline, None),
+            (pipeline.cell_ranges_pipeline, Some(cell_workgroups)),
+        ];
+        //passes without a workgroup count run once per live unit
+        for (id, workgroups) in passes {
+            pass.set_pipeline(pipeline_cache.get_compute_pipeline(id).unwrap());
+            match workgroups {
+                Some(workgroups) => pass.dispatch_workgroups(workgroups, 1, 1),
+                None => {
+                    pass.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::DISPATCH_OFFSET)
                 }
-                let mut pass_2 =
-                    render_context
-                        .command_encoder()
-                        .begin_compute_pass(&ComputePassDescriptor {
-                            label: Some(&"hash"),
-                            ..Default::default()
-                        });
-
-                let hash_id_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.hash_indices_pipeline)
-                    .unwrap();
-                pass_2.set_bind_group(0, bind_group, &[]);
-                pass_2.set_pipeline(hash_id_pipeline);
-
-                pass_2.dispatch_workgroups((COUNT as u32) / WORKGROUP_SIZE, 1, 1);
-
-                drop(pass_2);
-
-                let mut pass =
-                    render_context
-                        .command_encoder()
-                        .begin_compute_pass(&ComputePassDescriptor {
-                            label: Some(&"update"),
-                            ..Default::default()
-                        });
-
-                let update_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.update_pipeline)
-                    .unwrap();
-                pass.set_bind_group(0, bind_group, &[]);
-                pass.set_pipeline(update_pipeline);
-
-                pass.dispatch_workgroups((COUNT as u32) / WORKGROUP_SIZE, 1, 1);
-
-                drop(pass);
             }
         }
     }
+
+    /// Recounts the living units of every faction for the faction readback.
+    fn count_factions(&self, render_context: &mut RenderContext, world: &World) {
+        let bind_group = &world.resource::<LogicBindGroup>().bind_group;
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline = world.resource::<LogicPipeline>();
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("count factions"),
+                    ..Default::default()
+                });
+        pass.set_bind_group(0, bind_group, &[0]);
+
+        let clear_faction_counts_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.clear_faction_counts_pipeline)
+            .unwrap();
+        pass.set_pipeline(clear_faction_counts_pipeline);
+        pass.dispatch_workgroups(1, 1, 1);
+
+        let count_factions_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.count_factions_pipeline)
+            .unwrap();
+        pass.set_pipeline(count_factions_pipeline);
+        pass.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::DISPATCH_OFFSET);
+    }
+
+    /// Packs the units that are still alive to the front of the compacted buffer and shrinks the live count.
+    fn compact(&self, render_context: &mut RenderContext, world: &World) {
+        let bind_group = &world.resource::<LogicBindGroup>().bind_group;
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline = world.resource::<LogicPipeline>();
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("compaction"),
+                    ..Default::default()
+                });
+        pass.set_bind_group(0, bind_group, &[0]);
+
+        let compact_count_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.compact_count_pipeline)
+            .unwrap();
+        pass.set_pipeline(compact_count_pipeline);
+        pass.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::DISPATCH_OFFSET);
+
+        let compact_scan_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.compact_scan_pipeline)
+            .unwrap();
+        pass.set_pipeline(compact_scan_pipeline);
+        pass.dispatch_workgroups(1, 1, 1);
+
+        let compact_scatter_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.compact_scatter_pipeline)
+            .unwrap();
+        pass.set_pipeline(compact_scatter_pipeline);
+        pass.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::DISPATCH_OFFSET);
+
+        //rewrites the dispatch arguments, so it has to run after every pass that is sized by them
+        let compact_finish_pipeline = pipeline_cache
+            .get_compute_pipeline(pipeline.compact_finish_pipeline)
+            .unwrap();
+        pass.set_pipeline(compact_finish_pipeline);
+        pass.dispatch_workgroups(1, 1, 1);
+    }
 }
@@ -0,0 +1,15 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+/// A projectile in flight, it lands on `impact` once `ticks_left` reaches 0.
+#[derive(ShaderType, Default, Clone, Copy)]
+pub struct Projectile {
+    pub previous_position: Vec2,
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub impact: Vec2,
+    pub damage: i32,
+    pub faction: i32,
+    pub splash_radius: f32,
+    //0 once the projectile has landed, slots in this state are free
+    pub ticks_left: i32,
+}
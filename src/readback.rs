@@ -0,0 +1,390 @@
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Mutex,
+};
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::*,
+        renderer::{render_system, RenderContext, RenderDevice},
+        MainWorld, Render, RenderApp, RenderSet,
+    },
+};
+
+use crate::{
+    config::{SimulationBackend, SimulationConfig},
+    faction::FactionCounts,
+    projectile::Projectile,
+    replay::Replay,
+    timestep::clock::SimulationClock,
+    unit::{LiveUnits, Unit},
+    FactionCountsBuffer, LiveBuffer, ProjectileBuffer, ProjectileCounterBuffer, UnitBuffer,
+};
+
+//staging buffers in flight at once, a readback is skipped rather than waiting for one to free up
+const MAX_STAGING_BUFFERS: usize = 6;
+
+/// The latest unit state copied back from the GPU, replaced every `readback_interval` ticks.
+///
+/// `units` holds the first `live.count` slots of the unit buffer, which still includes units that
+/// died since the last compaction, so check `health` before counting a unit as alive.
+#[derive(Resource, Default)]
+pub struct UnitReadback {
+    //tick the copy was taken after
+    pub tick: u64,
+    pub live: LiveUnits,
+    pub units: Vec<Unit>,
+}
+
+/// Living units and kills of every faction, counted on the GPU and copied back once per simulated second.
+#[derive(Resource, Default)]
+pub struct FactionReadback {
+    pub tick: u64,
+    pub counts: Vec<FactionCounts>,
+}
+
+/// The projectiles in flight and the slot counter, only copied back when a readback is requested.
+#[derive(Resource, Default)]
+pub struct ProjectileReadback {
+    pub tick: u64,
+    pub counter: u32,
+    pub projectiles: Vec<Projectile>,
+}
+
+/// Readbacks asked for on top of the intervals, for example by a quicksave.
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct ReadbackRequests {
+    //total requests so far, every readback is copied once after every request that has not been served
+    requested: u64,
+}
+
+impl ReadbackRequests {
+    /// Copies the units, the faction counts and the projectiles back after the current frame's ticks.
+    pub fn request(&mut self) {
+        self.requested += 1;
+    }
+
+    pub fn requested(&self) -> u64 {
+        self.requested
+    }
+}
+
+pub struct ReadbackPlugin;
+impl Plugin for ReadbackPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<UnitReadback>()
+            .init_resource::<FactionReadback>()
+            .init_resource::<ProjectileReadback>()
+            .init_resource::<ReadbackRequests>();
+        //the CPU backend publishes the readbacks itself, a replay has nothing to read back
+        if app.world().resource::<SimulationConfig>().backend == SimulationBackend::Cpu
+            || app.world().contains_resource::<Replay>()
+        {
+            return;
+        }
+        app.add_plugins(ExtractResourcePlugin::<ReadbackRequests>::default());
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<ReadbackState>()
+            .add_systems(ExtractSchedule, publish_readback)
+            .add_systems(
+                Render,
+                (
+                    request_readback.in_set(RenderSet::PrepareResources),
+                    map_readback.after(render_system).in_set(RenderSet::Render),
+                ),
+            );
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReadbackKind {
+    Units,
+    Factions,
+    Projectiles,
+}
+
+struct StagingBuffer {
+    buffer: Buffer,
+    taken: bool,
+}
+
+/// A copy the logic node records at the end of this frame's ticks.
+pub struct ReadbackRequest {
+    buffer: Buffer,
+    tick: u64,
+    //bytes copied into the front of the staging buffer
+    size: u64,
+    //value of `ReadbackState::restores` when the copy was requested
+    restore: u64,
+}
+
+type Readback = (ReadbackKind, Buffer, u64, u64, Vec<u8>);
+
+#[derive(Resource)]
+pub struct ReadbackState {
+    staging: Vec<StagingBuffer>,
+    pub units: Option<ReadbackRequest>,
+    pub factions: Option<ReadbackRequest>,
+    pub projectiles: Option<ReadbackRequest>,
+    //units are never spawned mid battle, so the last live count bounds how much has to be copied
+    live_count: Option<u32>,
+    //times the unit buffer was overwritten, copies made before the latest one are dropped
+    restores: u64,
+    //requests of ReadbackRequests that have been copied
+    served: u64,
+    sender: Sender<Readback>,
+    receiver: Mutex<Receiver<Readback>>,
+}
+
+impl Default for ReadbackState {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            staging: Vec::new(),
+            units: None,
+            factions: None,
+            projectiles: None,
+            live_count: None,
+            restores: 0,
+            served: 0,
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl ReadbackState {
+    //a free staging buffer of exactly this size, or a new one while the pool has room
+    fn take_staging(&mut self, render_device: &RenderDevice, size: u64) -> Option<Buffer> {
+        if let Some(staging) = self
+            .staging
+            .iter_mut()
+            .find(|staging| !staging.taken && staging.buffer.size() == size)
+        {
+            staging.taken = true;
+            return Some(staging.buffer.clone());
+        }
+        if self.staging.len() >= MAX_STAGING_BUFFERS {
+            return None;
+        }
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        self.staging.push(StagingBuffer {
+            buffer: buffer.clone(),
+            taken: true,
+        });
+        Some(buffer)
+    }
+
+    /// The live count of the latest unit readback, the GPU may have compacted further since.
+    pub fn live_count(&self) -> Option<u32> {
+        self.live_count
+    }
+
+    /// Sets how many units the next readback copies, for when the unit buffer was overwritten.
+    pub fn set_live_count(&mut self, count: u32) {
+        self.live_count = Some(count);
+        self.restores += 1;
+    }
+
+    fn release_staging(&mut self, buffer: &Buffer) {
+        if let Some(staging) = self
+            .staging
+            .iter_mut()
+            .find(|staging| staging.buffer.id() == buffer.id())
+        {
+            staging.taken = false;
+        }
+    }
+}
+
+fn request_readback(
+    mut state: ResMut<ReadbackState>,
+    config: Res<SimulationConfig>,
+    clock: Res<SimulationClock>,
+    requests: Res<ReadbackRequests>,
+    render_device: Res<RenderDevice>,
+) {
+    let due = |interval: u64| {
+        interval != 0
+            && clock
+                .frame_ticks()
+                .any(|tick| tick.is_multiple_of(interval))
+    };
+    let requested = state.served < requests.requested();
+    if due(config.readback_interval as u64) || requested {
+        let capacity =
+            LiveUnits::min_size().get() + config.capacity() as u64 * Unit::min_size().get();
+        let unit_count = state.live_count.unwrap_or(config.count() as u32);
+        let restore = state.restores;
+        state.units = state
+            .take_staging(&render_device, capacity)
+            .map(|buffer| ReadbackRequest {
+                buffer,
+                tick: clock.tick(),
+                size: LiveUnits::min_size().get() + unit_count as u64 * Unit::min_size().get(),
+                restore,
+            });
+    }
+    //the clock stops at the time limit, which gets one last count so the result is exact
+    let time_limit = config
+        .victory
+        .time_limit
+        .is_some_and(|limit| clock.frame_ticks().contains(&limit));
+    if due(config.ticks_per_second()) || time_limit || requested {
+        let size = config.factions.len() as u64 * FactionCounts::min_size().get();
+        let restore = state.restores;
+        state.factions = state
+            .take_staging(&render_device, size)
+            .map(|buffer| ReadbackRequest {
+                buffer,
+                tick: clock.tick(),
+                size,
+                restore,
+            });
+    }
+    if requested {
+        let size =
+            u32::min_size().get() + config.max_projectiles as u64 * Projectile::min_size().get();
+        let restore = state.restores;
+        state.projectiles =
+            state
+                .take_staging(&render_device, size)
+                .map(|buffer| ReadbackRequest {
+                    buffer,
+                    tick: clock.tick(),
+                    size,
+                    restore,
+                });
+    }
+    //a request is retried next frame when the pool had no staging buffer left for it
+    if requested && state.units.is_some() && state.factions.is_some() && state.projectiles.is_some()
+    {
+        state.served = requests.requested();
+    }
+}
+
+/// Copies the requested buffers into their staging buffers, after this frame's ticks.
+pub fn copy_to_staging(render_context: &mut RenderContext, world: &World) {
+    let state = world.resource::<ReadbackState>();
+    let encoder = render_context.command_encoder();
+    if let Some(request) = &state.units {
+        let live_buffer = &world.resource::<LiveBuffer>().0[0];
+        let unit_buffer = &world.resource::<UnitBuffer>().0[0];
+        let live_size = live_buffer.size();
+        encoder.copy_buffer_to_buffer(live_buffer, 0, &request.buffer, 0, live_size);
+        encoder.copy_buffer_to_buffer(
+            unit_buffer,
+            0,
+            &request.buffer,
+            live_size,
+            request.size - live_size,
+        );
+    }
+    if let Some(request) = &state.factions {
+        let faction_counts_buffer = &world.resource::<FactionCountsBuffer>().0[0];
+        encoder.copy_buffer_to_buffer(faction_counts_buffer, 0, &request.buffer, 0, request.size);
+    }
+    if let Some(request) = &state.projectiles {
+        let counter_buffer = &world.resource::<ProjectileCounterBuffer>().0[0];
+        let projectile_buffer = &world.resource::<ProjectileBuffer>().0[0];
+        let counter_size = u32::min_size().get();
+        encoder.copy_buffer_to_buffer(counter_buffer, 0, &request.buffer, 0, counter_size);
+        encoder.copy_buffer_to_buffer(
+            projectile_buffer,
+            0,
+            &request.buffer,
+            counter_size,
+            request.size - counter_size,
+        );
+    }
+}
+
+//mapping has to wait until the copy has been submitted, the callback fires once the GPU is done with it
+fn map_readback(mut state: ResMut<ReadbackState>) {
+    let requests = [
+        (ReadbackKind::Units, state.units.take()),
+        (ReadbackKind::Factions, state.factions.take()),
+        (ReadbackKind::Projectiles, state.projectiles.take()),
+    ];
+    for (kind, request) in requests {
+        let Some(request) = request else {
+            continue;
+        };
+        let buffer = request.buffer.clone();
+        let sender = state.sender.clone();
+        let size = request.size;
+        let restore = request.restore;
+        request
+            .buffer
+            .slice(..size)
+            .map_async(MapMode::Read, move |result| {
+                //an empty copy still hands the staging buffer back
+                let mut data = Vec::new();
+                match result {
+                    Ok(()) => {
+                        data = buffer.slice(..size).get_mapped_range().to_vec();
+                        buffer.unmap();
+                    }
+                    Err(err) => warn!("failed to map a readback buffer: {err}"),
+                }
+                let _ = sender.send((kind, buffer, request.tick, restore, data));
+            });
+    }
+}
+
+fn publish_readback(mut main_world: ResMut<MainWorld>, mut state: ResMut<ReadbackState>) {
+    let received: Vec<_> = state.receiver.lock().unwrap().try_iter().collect();
+    //only the newest copy of each kind is published when several finished since the last frame
+    let mut latest_units = None;
+    let mut latest_factions = None;
+    let mut latest_projectiles = None;
+    for (kind, buffer, tick, restore, data) in received {
+        state.release_staging(&buffer);
+        //copied before the unit buffer was overwritten, its live count could be too small for the new units
+        if data.is_empty() || restore != state.restores {
+            continue;
+        }
+        let latest = match kind {
+            ReadbackKind::Units => &mut latest_units,
+            ReadbackKind::Factions => &mut latest_factions,
+            ReadbackKind::Projectiles => &mut latest_projectiles,
+        };
+        if latest
+            .as_ref()
+            .is_none_or(|(latest_tick, _)| *latest_tick < tick)
+        {
+            *latest = Some((tick, data));
+        }
+    }
+    if let Some((tick, data)) = latest_units {
+        let (live, units) = data.split_at(LiveUnits::min_size().get() as usize);
+        let live: LiveUnits = encase::StorageBuffer::new(live).create().unwrap();
+        let mut units: Vec<Unit> = encase::StorageBuffer::new(units).create().unwrap();
+        units.truncate(live.count as usize);
+        state.live_count = Some(live.count);
+        main_world.insert_resource(UnitReadback { tick, live, units });
+    }
+    if let Some((tick, data)) = latest_factions {
+        let counts: Vec<FactionCounts> = encase::StorageBuffer::new(&data).create().unwrap();
+        main_world.insert_resource(FactionReadback { tick, counts });
+    }
+    if let Some((tick, data)) = latest_projectiles {
+        let (counter, projectiles) = data.split_at(u32::min_size().get() as usize);
+        let counter: u32 = encase::StorageBuffer::new(counter).create().unwrap();
+        let projectiles: Vec<Projectile> =
+            encase::StorageBuffer::new(projectiles).create().unwrap();
+        main_world.insert_resource(ProjectileReadback {
+            tick,
+            counter,
+            projectiles,
+        });
+    }
+}
@@ -9,9 +9,14 @@ use bevy::render::{
     texture::GpuImage,
 };
 
-use crate::helpers::helpers::get_pipeline_states;
-use crate::timestep::fixed_time::FixedTimestep;
-use crate::{SimulationUniforms, UnitBuffer, COUNT, SIZE, WORKGROUP_SIZE};
+use crate::config::SimulationConfig;
+use crate::helpers::helpers::{get_pipeline_states, storage_buffer};
+use crate::timestep::clock::SimulationClock;
+use crate::unit::LiveUnits;
+use crate::{
+    shader_defs, DispatchBuffer, FactionBuffer, LiveBuffer, ProjectileBuffer, SimulationUniforms,
+    UnitBuffer,
+};
 const SHADER_ASSET_PATH: &str = "shaders/rendering.wgsl";
 
 #[derive(PartialEq)]
@@ -40,8 +45,13 @@ pub fn prepare_bind_group(
     pipeline: Res<RenderingPipeline>,
     gpu_images: Res<RenderAssets<GpuImage>>,
     simulation_uniforms: Res<SimulationUniforms>,
-    unit_buffer: Res<UnitBuffer>,
-    fixed: Res<FixedTimestep>,
+    (unit_buffer, faction_buffer, projectile_buffer, live_buffer): (
+        Res<UnitBuffer>,
+        Res<FactionBuffer>,
+        Res<ProjectileBuffer>,
+        Res<LiveBuffer>,
+    ),
+    clock: Res<SimulationClock>,
     //uniform_buffer: Res<SimulationUniformBuffer>,
     render_device: Res<RenderDevice>,
 ) {
@@ -49,16 +59,13 @@ pub fn prepare_bind_group(
 
     let mut uniform_data = simulation_uniforms.data.clone().unwrap();
 
-    uniform_data.alpha = fixed.alpha;
-    let mut byte_buffer = Vec::new();
-    let mut buffer = encase::StorageBuffer::new(&mut byte_buffer);
-    buffer.write(&uniform_data).unwrap();
-
-    let uniform = render_device.create_buffer_with_data(&BufferInitDescriptor {
-        label: None,
-        usage: BufferUsages::COPY_DST | BufferUsages::UNIFORM | BufferUsages::COPY_SRC,
-        contents: buffer.into_inner(),
-    });
+    uniform_data.alpha = clock.alpha();
+    let uniform = storage_buffer(
+        &render_device,
+        "render uniforms",
+        &uniform_data,
+        BufferUsages::COPY_DST | BufferUsages::UNIFORM | BufferUsages::COPY_SRC,
+    );
 
     let bind_group = render_device.create_bind_group(
         None,
@@ -76,6 +83,20 @@ pub fn prepare_bind_group(
                 binding: 2,
                 resource: BindingResource::Buffer(uniform.as_entire_buffer_binding()),
             },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::Buffer(faction_buffer.0[0].as_entire_buffer_binding()),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::Buffer(
+                    projectile_buffer.0[0].as_entire_buffer_binding(),
+                ),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: BindingResource::Buffer(live_buffer.0[0].as_entire_buffer_binding()),
+            },
         ],
     );
     commands.insert_resource(RenderBindGroup(bind_group));
@@ -86,6 +107,7 @@ pub struct RenderingPipeline {
     texture_bind_group_layout: BindGroupLayout,
     update_pipeline: CachedComputePipelineId,
     clear_pipeline: CachedComputePipelineId,
+    projectile_pipeline: CachedComputePipelineId,
 }
 
 impl FromWorld for RenderingPipeline {
@@ -124,8 +146,39 @@ impl FromWorld for RenderingPipeline {
                     },
                     count: None,
                 },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         );
+        let shader_defs = shader_defs(world.resource::<SimulationConfig>());
         let shader = world.load_asset(SHADER_ASSET_PATH);
         let pipeline_cache = world.resource::<PipelineCache>();
         let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
@@ -133,7 +186,7 @@ impl FromWorld for RenderingPipeline {
             layout: vec![texture_bind_group_layout.clone()],
             push_constant_ranges: Vec::new(),
             shader: shader.clone(),
-            shader_defs: vec![],
+            shader_defs: shader_defs.clone(),
             entry_point: Cow::from("render"),
             zero_initialize_workgroup_memory: false,
         });
@@ -142,16 +195,28 @@ impl FromWorld for RenderingPipeline {
             label: None,
             layout: vec![texture_bind_group_layout.clone()],
             push_constant_ranges: Vec::new(),
-            shader,
-            shader_defs: vec![],
+            shader: shader.clone(),
+            shader_defs: shader_defs.clone(),
             entry_point: Cow::from("clear"),
             zero_initialize_workgroup_memory: false,
         });
 
+        let projectile_pipeline =
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: None,
+                layout: vec![texture_bind_group_layout.clone()],
+                push_constant_ranges: Vec::new(),
+                shader,
+                shader_defs,
+                entry_point: Cow::from("render_projectiles"),
+                zero_initialize_workgroup_memory: false,
+            });
+
         RenderingPipeline {
             texture_bind_group_layout,
             clear_pipeline,
             update_pipeline,
+            projectile_pipeline,
         }
     }
 }
@@ -162,8 +227,12 @@ impl render_graph::Node for RenderNode {
         let pipeline_cache = world.resource::<PipelineCache>();
 
         if self.state == RenderState::Loading {
-            let ids = vec![pipeline.update_pipeline, pipeline.clear_pipeline];
-            if get_pipeline_states(ids, &pipeline_cache, SHADER_ASSET_PATH.to_owned()) {
+            let ids = vec![
+                pipeline.update_pipeline,
+                pipeline.clear_pipeline,
+                pipeline.projectile_pipeline,
+            ];
+            if get_pipeline_states(ids, pipeline_cache, SHADER_ASSET_PATH.to_owned()) {
                 self.state = RenderState::Update;
             }
         }
@@ -178,6 +247,9 @@ impl render_graph::Node for RenderNode {
         let bind_group = &world.resource::<RenderBindGroup>().0;
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<RenderingPipeline>();
+        let config = world.resource::<SimulationConfig>();
+        let dispatch_buffer = &world.resource::<DispatchBuffer>().0[0];
+        let size = config.size();
 
         // select the pipeline based on the current state
         match self.state {
@@ -187,7 +259,7 @@ impl render_graph::Node for RenderNode {
                     render_context
                         .command_encoder()
                         .begin_compute_pass(&ComputePassDescriptor {
-                            label: Some(&"Render Pass"),
+                            label: Some("Render Pass"),
                             ..Default::default()
                         });
 
@@ -197,7 +269,7 @@ impl render_graph::Node for RenderNode {
                 pass.set_bind_group(0, bind_group, &[]);
                 pass.set_pipeline(clear_pipeline);
 
-                pass.dispatch_workgroups(SIZE.0 / 32, SIZE.1 / 32, 1);
+                pass.dispatch_workgroups(size.0 / 32, size.1 / 32, 1);
 
                 let update_pipeline = pipeline_cache
                     .get_compute_pipeline(pipeline.update_pipeline)
@@ -205,7 +277,20 @@ impl render_graph::Node for RenderNode {
                 pass.set_bind_group(0, bind_group, &[]);
                 pass.set_pipeline(update_pipeline);
 
-                pass.dispatch_workgroups((COUNT as u32) / WORKGROUP_SIZE, 1, 1);
+                pass.dispatch_workgroups_indirect(dispatch_buffer, LiveUnits::DISPATCH_OFFSET);
+
+                //drawn after the units so arrows stay visible over dense blocks
+                let projectile_pipeline = pipeline_cache
+                    .get_compute_pipeline(pipeline.projectile_pipeline)
+                    .unwrap();
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.set_pipeline(projectile_pipeline);
+
+                pass.dispatch_workgroups(
+                    config.max_projectiles.div_ceil(config.workgroup_size),
+                    1,
+                    1,
+                );
             }
         }
 
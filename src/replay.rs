@@ -0,0 +1,714 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    prelude::*,
+    render::{renderer::RenderQueue, Extract, RenderApp},
+};
+
+use crate::{
+    config::{ConfigError, SimulationConfig},
+    helpers::{
+        bytes::{write_varint, ByteReader, EndOfFile},
+        helpers::write_buffer,
+    },
+    readback::UnitReadback,
+    timestep::clock::SimulationClock,
+    unit::{LiveUnits, Unit},
+    DispatchBuffer, LiveBuffer, SimulationUniforms, UnitBuffer,
+};
+
+const MAGIC: &[u8; 8] = b"BTLREPL\0";
+/// Bumped whenever the header or the frame encoding changes, older files are rejected.
+pub const REPLAY_VERSION: u32 = 2;
+//frames between two keyframes, so `KEYFRAME_INTERVAL * frame_interval` ticks, a seek decodes at most this many deltas
+const KEYFRAME_INTERVAL: usize = 32;
+//positions are stored in 1/64 of a world unit
+const POSITION_SCALE: f32 = 64.0;
+const MORALE_SCALE: f32 = 255.0;
+//decoded frames kept around, two for the frames the shown tick lies between and one for the tick before
+const DECODED_FRAMES: usize = 3;
+const SEEK_SECONDS: f32 = 5.0;
+
+//x, y, health and morale with the routing flag in the lowest bit, quantized so frames can be stored as deltas
+type UnitState = [i32; 4];
+
+fn unit_state(unit: &Unit) -> UnitState {
+    let morale = (unit.morale.clamp(0.0, 1.0) * MORALE_SCALE).round() as i32;
+    [
+        (unit.current_state.x * POSITION_SCALE).round() as i32,
+        (unit.current_state.y * POSITION_SCALE).round() as i32,
+        unit.health,
+        (morale << 1) | unit.routing,
+    ]
+}
+
+/// The units of a replay that never change, indexed by unit id.
+#[derive(Clone, Debug)]
+pub struct ReplayHeader {
+    pub seed: u64,
+    pub tick_rate: f32,
+    //ticks between two recorded frames, the readback_interval of the recording run
+    pub frame_interval: u32,
+    pub world_size: (i32, i32),
+    pub faction_count: u32,
+    pub unit_type_count: u32,
+    pub factions: Vec<i32>,
+    pub unit_types: Vec<i32>,
+}
+
+struct Frame {
+    tick: u64,
+    //keyframes hold every unit as is, the other frames the difference to the frame before
+    keyframe: bool,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io {
+        path: String,
+        error: std::io::Error,
+    },
+    //not a replay, a newer version or cut short
+    Format(String),
+    //the replay was recorded with factions or unit types the config does not have
+    Mismatch {
+        field: &'static str,
+        recorded: u32,
+        current: u32,
+    },
+    Unsupported(&'static str),
+    //the recorded unit count or tick rate do not make a valid config
+    Config(ConfigError),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io { path, error } => write!(f, "could not access {path}: {error}"),
+            ReplayError::Format(reason) => write!(f, "not a valid replay: {reason}"),
+            ReplayError::Mismatch {
+                field,
+                recorded,
+                current,
+            } => write!(
+                f,
+                "replay does not fit the config: it was recorded with {recorded} `{field}` and there are {current} here"
+            ),
+            ReplayError::Unsupported(reason) => write!(f, "{reason}"),
+            ReplayError::Config(error) => write!(f, "replay does not make a valid config: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<ConfigError> for ReplayError {
+    fn from(error: ConfigError) -> Self {
+        ReplayError::Config(error)
+    }
+}
+
+impl From<EndOfFile> for ReplayError {
+    fn from(_: EndOfFile) -> Self {
+        ReplayError::Format("the file ends early".to_owned())
+    }
+}
+
+fn io_error(path: &Path, error: std::io::Error) -> ReplayError {
+    ReplayError::Io {
+        path: path.display().to_string(),
+        error,
+    }
+}
+
+fn encode_frame(states: &[UnitState], previous: Option<&[UnitState]>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (index, state) in states.iter().enumerate() {
+        let base = previous.map_or([0; 4], |previous| previous[index]);
+        for (value, base) in state.iter().zip(base) {
+            write_varint(&mut bytes, *value as i64 - base as i64);
+        }
+    }
+    bytes
+}
+
+//applies a frame on top of the states of the frame before it, keyframes overwrite them
+fn decode_frame(frame: &Frame, states: &mut [UnitState]) -> Result<(), ReplayError> {
+    let mut reader = ByteReader::new(&frame.bytes);
+    for state in states.iter_mut() {
+        for value in state.iter_mut() {
+            let base = if frame.keyframe { 0 } else { *value as i64 };
+            *value = (base + reader.varint()?) as i32;
+        }
+    }
+    if !reader.is_empty() {
+        return Err(ReplayError::Format(format!(
+            "frame of tick {} holds more units than the header",
+            frame.tick
+        )));
+    }
+    Ok(())
+}
+
+/// A recorded battle: the header followed by the unit states of every readback, in tick order.
+/// A frame is recorded about every `frame_interval` ticks, at the end of the frame that reached it,
+/// and the ticks in between are interpolated when they are shown.
+///
+/// Only what the renderer needs is stored, so a replay can be shown but not simulated further.
+#[derive(Resource)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    frames: Vec<Frame>,
+}
+
+impl Replay {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|error| io_error(path, error))?;
+        let mut reader = ByteReader::new(&bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(ReplayError::Format("missing the replay marker".to_owned()));
+        }
+        let version = reader.u32()?;
+        if version != REPLAY_VERSION {
+            return Err(ReplayError::Format(format!(
+                "version {version} can not be read, expected version {REPLAY_VERSION}"
+            )));
+        }
+        let seed = reader.u64()?;
+        let tick_rate = reader.f32()?;
+        let frame_interval = reader.u32()?;
+        let world_size = (reader.i32()?, reader.i32()?);
+        let faction_count = reader.u32()?;
+        let unit_type_count = reader.u32()?;
+        let unit_count = reader.u32()?;
+        //every unit takes at least a byte for its faction and one for its unit type
+        if unit_count == 0 || unit_count as usize > reader.len() / 2 {
+            return Err(ReplayError::Format(format!(
+                "{unit_count} units can not be recorded in the rest of the file"
+            )));
+        }
+        let mut factions = Vec::with_capacity(unit_count as usize);
+        let mut unit_types = Vec::with_capacity(unit_count as usize);
+        for _ in 0..unit_count {
+            factions.push(reader.varint()? as i32);
+            unit_types.push(reader.varint()? as i32);
+        }
+        if !(tick_rate.is_finite() && tick_rate > 0.0) {
+            return Err(ReplayError::Format("invalid tick rate".to_owned()));
+        }
+        if frame_interval == 0 {
+            return Err(ReplayError::Format("invalid frame interval".to_owned()));
+        }
+        if factions
+            .iter()
+            .any(|&faction| faction as u32 >= faction_count)
+            || unit_types
+                .iter()
+                .any(|&unit_type| unit_type as u32 >= unit_type_count)
+        {
+            return Err(ReplayError::Format(
+                "a unit has an unknown faction or unit type".to_owned(),
+            ));
+        }
+
+        //frames are only indexed here, they are decoded when they are shown
+        let mut frames: Vec<Frame> = Vec::new();
+        while !reader.is_empty() {
+            let tick = reader.u64()?;
+            let keyframe = reader.u8()? != 0;
+            let len = reader.u32()? as usize;
+            let bytes = reader.take(len)?.to_vec();
+            if frames.last().is_some_and(|last| last.tick >= tick) {
+                return Err(ReplayError::Format(format!(
+                    "frame of tick {tick} is out of order"
+                )));
+            }
+            if frames.is_empty() && !keyframe {
+                return Err(ReplayError::Format(
+                    "the first frame is not a keyframe".to_owned(),
+                ));
+            }
+            frames.push(Frame {
+                tick,
+                keyframe,
+                bytes,
+            });
+        }
+        if frames.is_empty() {
+            return Err(ReplayError::Format("no frames were recorded".to_owned()));
+        }
+        Ok(Self {
+            header: ReplayHeader {
+                seed,
+                tick_rate,
+                frame_interval,
+                world_size,
+                faction_count,
+                unit_type_count,
+                factions,
+                unit_types,
+            },
+            frames,
+        })
+    }
+
+    /// Checks that the factions and unit types of the replay exist in the running config.
+    pub fn check(&self, config: &SimulationConfig) -> Result<(), ReplayError> {
+        let header = &self.header;
+        if header.faction_count as usize != config.factions.len() {
+            return Err(ReplayError::Mismatch {
+                field: "factions",
+                recorded: header.faction_count,
+                current: config.factions.len() as u32,
+            });
+        }
+        if header.unit_type_count as usize != config.unit_types.len() {
+            return Err(ReplayError::Mismatch {
+                field: "unit_types",
+                recorded: header.unit_type_count,
+                current: config.unit_types.len() as u32,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn unit_count(&self) -> usize {
+        self.header.factions.len()
+    }
+
+    pub fn first_tick(&self) -> u64 {
+        self.frames[0].tick
+    }
+
+    pub fn last_tick(&self) -> u64 {
+        self.frames[self.frames.len() - 1].tick
+    }
+}
+
+/// Writes a frame for every unit readback, so every `readback_interval` ticks, started with `--record`.
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    file: File,
+    path: PathBuf,
+    //states of the last recorded frame, indexed by unit id
+    states: Vec<UnitState>,
+    frames: usize,
+    last_tick: u64,
+}
+
+impl ReplayRecorder {
+    /// Creates the replay file, the header is written once the units have been spawned.
+    pub fn create(path: impl AsRef<Path>, config: &SimulationConfig) -> Result<Self, ReplayError> {
+        if config.readback_interval == 0 {
+            return Err(ReplayError::Unsupported(
+                "recording a replay needs a readback_interval greater than 0",
+            ));
+        }
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|error| io_error(path, error))?;
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+            states: Vec::new(),
+            frames: 0,
+            last_tick: 0,
+        })
+    }
+
+    fn write_frame(
+        &mut self,
+        tick: u64,
+        previous: Option<Vec<UnitState>>,
+    ) -> Result<(), ReplayError> {
+        let keyframe = self.frames.is_multiple_of(KEYFRAME_INTERVAL);
+        let payload = encode_frame(&self.states, previous.as_deref().filter(|_| !keyframe));
+        let mut bytes = Vec::with_capacity(payload.len() + 13);
+        bytes.extend_from_slice(&tick.to_le_bytes());
+        bytes.push(keyframe as u8);
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        //written in one go, so a run that is cut short still leaves a readable replay behind
+        self.file
+            .write_all(&bytes)
+            .map_err(|error| io_error(&self.path, error))?;
+        self.frames += 1;
+        self.last_tick = tick;
+        Ok(())
+    }
+}
+
+/// Records replays when a `ReplayRecorder` is inserted and plays back a `Replay` in place of the
+/// simulation, both are inserted before the plugin is added.
+pub struct ReplayPlugin;
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        if app.world().contains_resource::<ReplayRecorder>() {
+            app.add_systems(PostStartup, start_recording)
+                .add_systems(Update, record_frame);
+        }
+        if app.world().contains_resource::<Replay>() {
+            app.init_resource::<ReplayPlayback>()
+                .add_systems(PostStartup, start_playback)
+                .add_systems(Update, (replay_controls, show_tick).chain());
+            app.sub_app_mut(RenderApp)
+                .add_systems(ExtractSchedule, upload_playback);
+        }
+    }
+}
+
+//the spawned units make up the header and the first frame
+fn start_recording(
+    mut commands: Commands,
+    config: Res<SimulationConfig>,
+    uniforms: Res<SimulationUniforms>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    let units: Vec<&Unit> = uniforms.units.iter().filter(|unit| unit.id >= 0).collect();
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&REPLAY_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&config.seed().to_le_bytes());
+    bytes.extend_from_slice(&config.tick_rate.to_le_bytes());
+    bytes.extend_from_slice(&config.readback_interval.to_le_bytes());
+    bytes.extend_from_slice(&config.world_size.0.to_le_bytes());
+    bytes.extend_from_slice(&config.world_size.1.to_le_bytes());
+    bytes.extend_from_slice(&(config.factions.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(config.unit_types.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(units.len() as u32).to_le_bytes());
+    let mut states = vec![[0; 4]; units.len()];
+    let mut statics = vec![(0, 0); units.len()];
+    for unit in units {
+        states[unit.id as usize] = unit_state(unit);
+        statics[unit.id as usize] = (unit.faction, unit.unit_type);
+    }
+    for (faction, unit_type) in statics {
+        write_varint(&mut bytes, faction as i64);
+        write_varint(&mut bytes, unit_type as i64);
+    }
+    recorder.states = states;
+    let result = recorder
+        .file
+        .write_all(&bytes)
+        .map_err(|error| io_error(&recorder.path, error))
+        .and_then(|()| recorder.write_frame(0, None));
+    if let Err(err) = result {
+        warn!("stopped recording the replay: {err}");
+        commands.remove_resource::<ReplayRecorder>();
+    }
+}
+
+fn record_frame(
+    mut commands: Commands,
+    readback: Res<UnitReadback>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    if !readback.is_changed() || readback.tick <= recorder.last_tick {
+        return;
+    }
+    let previous = recorder.states.clone();
+    //units that were compacted away are dead, they keep their last position
+    for state in &mut recorder.states {
+        state[2] = state[2].min(0);
+    }
+    for unit in readback.units.iter().filter(|unit| unit.id >= 0) {
+        if let Some(state) = recorder.states.get_mut(unit.id as usize) {
+            *state = unit_state(unit);
+        }
+    }
+    if let Err(err) = recorder.write_frame(readback.tick, Some(previous)) {
+        warn!("stopped recording the replay: {err}");
+        commands.remove_resource::<ReplayRecorder>();
+    }
+}
+
+/// The units shown for the current tick of a replay, rebuilt whenever the tick changes.
+#[derive(Resource, Default)]
+pub struct ReplayPlayback {
+    //frame index and states of the last few decoded frames, playing forwards decodes one delta at a time
+    decoded: Vec<(usize, Vec<UnitState>)>,
+    //tick and direction the units were built for
+    shown: Option<(u64, bool)>,
+    units: Vec<Unit>,
+    //plays towards the first tick, the clock still counts forwards and is moved back after every frame
+    reverse: bool,
+}
+
+impl ReplayPlayback {
+    fn decode(&mut self, replay: &Replay, index: usize) -> Result<&[UnitState], ReplayError> {
+        if let Some(position) = self
+            .decoded
+            .iter()
+            .position(|(decoded, _)| *decoded == index)
+        {
+            return Ok(&self.decoded[position].1);
+        }
+        let keyframe = (0..=index)
+            .rev()
+            .find(|&index| replay.frames[index].keyframe)
+            .unwrap();
+        //continue from the closest decoded frame since that keyframe, or decode the keyframe itself
+        let start = self
+            .decoded
+            .iter()
+            .filter(|(decoded, _)| (keyframe..index).contains(decoded))
+            .max_by_key(|(decoded, _)| *decoded);
+        let (first, mut states) = match start {
+            Some((decoded, states)) => (decoded + 1, states.clone()),
+            None => (keyframe, vec![[0; 4]; replay.unit_count()]),
+        };
+        for frame in &replay.frames[first..=index] {
+            decode_frame(frame, &mut states)?;
+        }
+        if self.decoded.len() >= DECODED_FRAMES {
+            self.decoded.remove(0);
+        }
+        self.decoded.push((index, states));
+        Ok(&self.decoded[self.decoded.len() - 1].1)
+    }
+
+    //positions, health, morale and routing of every unit at a tick, interpolated between the frames around it
+    fn states_at(
+        &mut self,
+        replay: &Replay,
+        tick: u64,
+    ) -> Result<Vec<(Vec2, i32, f32, i32)>, ReplayError> {
+        let next = replay.frames.partition_point(|frame| frame.tick <= tick);
+        let index = next.saturating_sub(1);
+        let earlier = self.decode(replay, index)?.to_vec();
+        let (later, t) = match replay.frames.get(next) {
+            Some(frame) => {
+                let start = replay.frames[index].tick;
+                let t = tick.saturating_sub(start) as f32 / (frame.tick - start) as f32;
+                (self.decode(replay, next)?.to_vec(), t)
+            }
+            None => (earlier.clone(), 0.0),
+        };
+        let half_world = Vec2::new(
+            replay.header.world_size.0 as f32,
+            replay.header.world_size.1 as f32,
+        ) / 2.0;
+        Ok(earlier
+            .iter()
+            .zip(&later)
+            .map(|(a, b)| {
+                let from = Vec2::new(a[0] as f32, a[1] as f32) / POSITION_SCALE;
+                let to = Vec2::new(b[0] as f32, b[1] as f32) / POSITION_SCALE;
+                //a unit that wrapped around the world jumps instead of crossing it
+                let position = if (to - from).abs().cmpgt(half_world).any() {
+                    from
+                } else {
+                    from.lerp(to, t)
+                };
+                let (from_morale, to_morale) = ((a[3] >> 1) as f32, (b[3] >> 1) as f32);
+                let morale = (from_morale + (to_morale - from_morale) * t) / MORALE_SCALE;
+                (position, a[2], morale, a[3] & 1)
+            })
+            .collect())
+    }
+
+    /// Shows the units at `tick`, moving from where they were a tick earlier so rendering can interpolate.
+    /// In reverse they move from `tick` towards the tick before instead.
+    fn show(&mut self, replay: &Replay, tick: u64) -> Result<(), ReplayError> {
+        let before = tick.saturating_sub(1).max(replay.first_tick());
+        let (from, to) = if self.reverse {
+            (tick, before)
+        } else {
+            (before, tick)
+        };
+        let previous = self.states_at(replay, from)?;
+        let current = self.states_at(replay, to)?;
+        let header = &replay.header;
+        self.units = previous
+            .iter()
+            .zip(current)
+            .enumerate()
+            .map(
+                |(id, (&(previous, ..), (current, health, morale, routing)))| Unit {
+                    previous_state: previous,
+                    current_state: current,
+                    velocity: current - previous,
+                    hash_id: 0,
+                    attack_id: -1,
+                    id: id as i32,
+                    health,
+                    faction: header.factions[id],
+                    unit_type: header.unit_types[id],
+                    reload: 0,
+                    morale,
+                    routing,
+                },
+            )
+            .collect();
+        self.shown = Some((tick, self.reverse));
+        Ok(())
+    }
+}
+
+//the clock runs over the recorded ticks and stops at the last frame, so pause and speed work as usual
+fn start_playback(replay: Res<Replay>, mut clock: ResMut<SimulationClock>) {
+    info!(
+        "replaying ticks {} to {}, recorded every {} ticks",
+        replay.first_tick(),
+        replay.last_tick(),
+        replay.header.frame_interval
+    );
+    clock.restore(replay.first_tick());
+    clock.set_stop_at(Some(replay.last_tick()));
+    clock.start();
+}
+
+fn replay_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    replay: Res<Replay>,
+    mut playback: ResMut<ReplayPlayback>,
+    mut clock: ResMut<SimulationClock>,
+    mut fixed: ResMut<Time<Fixed>>,
+) {
+    let (first, last) = (replay.first_tick(), replay.last_tick());
+    if playback.reverse {
+        let ticks = clock.frame_ticks().count() as u64;
+        let rewind = (2 * ticks).min(clock.tick() - first);
+        clock.rewind(rewind);
+    }
+    if keys.just_pressed(KeyCode::KeyR) {
+        playback.reverse = !playback.reverse;
+        //in reverse the clock has to tick past the last frame, every tick is taken back twice
+        clock.set_stop_at((!playback.reverse).then_some(last));
+    }
+    let seek = (SEEK_SECONDS * replay.header.tick_rate) as u64;
+    let digits = [
+        KeyCode::Digit0,
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ];
+    let mut target = None;
+    if keys.just_pressed(KeyCode::Home) {
+        target = Some(first);
+    }
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        target = Some(clock.tick().saturating_sub(seek).max(first));
+    }
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        target = Some((clock.tick() + seek).min(last));
+    }
+    //digit keys jump to that tenth of the replay
+    if let Some(digit) = digits.iter().position(|&key| keys.just_pressed(key)) {
+        target = Some(first + (last - first) * digit as u64 / 10);
+    }
+    if let Some(tick) = target {
+        clock.restore(tick);
+        let overstep = fixed.overstep();
+        fixed.discard_overstep(overstep);
+    }
+}
+
+fn show_tick(
+    replay: Res<Replay>,
+    clock: Res<SimulationClock>,
+    mut playback: ResMut<ReplayPlayback>,
+) {
+    if playback.shown == Some((clock.tick(), playback.reverse)) {
+        return;
+    }
+    if let Err(err) = playback.show(&replay, clock.tick()) {
+        //the units stay where they were, later ticks may still decode
+        error!("could not show tick {} of the replay: {err}", clock.tick());
+        playback.shown = Some((clock.tick(), playback.reverse));
+    }
+}
+
+//writes the shown units over the buffers the logic node would have filled, there is nothing to simulate
+fn upload_playback(
+    config: Res<SimulationConfig>,
+    playback: Extract<Res<ReplayPlayback>>,
+    render_queue: Res<RenderQueue>,
+    unit_buffer: Res<UnitBuffer>,
+    live_buffer: Res<LiveBuffer>,
+    dispatch_buffer: Res<DispatchBuffer>,
+    mut uploaded: Local<bool>,
+) {
+    //the buffers are created on the first render and start out with units spawned from the config
+    if unit_buffer.0.is_empty()
+        || playback.units.is_empty()
+        || (*uploaded && !playback.is_changed())
+    {
+        return;
+    }
+    *uploaded = true;
+    write_buffer(&render_queue, &unit_buffer.0[0], &playback.units);
+
+    let live = LiveUnits::new(
+        playback.units.len() as u32,
+        config.capacity() as u32,
+        config.workgroup_size,
+    );
+    write_buffer(&render_queue, &live_buffer.0[0], &live);
+    write_buffer(&render_queue, &dispatch_buffer.0[0], &live);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(keyframe: bool, bytes: Vec<u8>) -> Frame {
+        Frame {
+            tick: 1,
+            keyframe,
+            bytes,
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let first = vec![
+            [0, 0, 100, 511],
+            [-640, 1280, 75, 0],
+            [i32::MAX, i32::MIN, 0, 1],
+        ];
+        let second = vec![
+            [3, -2, 90, 500],
+            [-700, 1300, 0, 1],
+            [i32::MIN, i32::MAX, 0, 1],
+        ];
+
+        let mut states = vec![[7; 4]; first.len()];
+        decode_frame(&frame(true, encode_frame(&first, None)), &mut states).unwrap();
+        assert_eq!(states, first);
+
+        let delta = encode_frame(&second, Some(&first));
+        decode_frame(&frame(false, delta), &mut states).unwrap();
+        assert_eq!(states, second);
+    }
+
+    #[test]
+    fn unchanged_units_take_a_byte_per_field() {
+        let states = vec![[-640, 1280, 75, 0]; 8];
+        assert_eq!(encode_frame(&states, Some(&states)).len(), states.len() * 4);
+    }
+
+    #[test]
+    fn frames_of_another_size_are_rejected() {
+        let states = vec![[1, 2, 3, 4]; 4];
+        let bytes = encode_frame(&states, None);
+        let mut fewer = vec![[0; 4]; 3];
+        assert!(matches!(
+            decode_frame(&frame(true, bytes.clone()), &mut fewer),
+            Err(ReplayError::Format(_))
+        ));
+        let mut more = vec![[0; 4]; 5];
+        assert!(decode_frame(&frame(true, bytes), &mut more).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::{f32::consts::PI, fs, path::Path};
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, FactionConfig, SimulationConfig};
+use crate::unit::Unit;
+
+/// A battle setup: the armies to spawn and, optionally, the factions they belong to.
+#[derive(Resource, Clone, Debug, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: String,
+    //replace the factions and alliances of the config file when present
+    #[serde(default)]
+    pub factions: Option<Vec<FactionConfig>>,
+    #[serde(default)]
+    pub alliances: Option<Vec<(u32, u32)>>,
+    pub armies: Vec<Army>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Army {
+    pub faction: u32,
+    //name of an entry in the config's unit_types
+    pub unit_type: String,
+    pub count: u32,
+    pub formation: Formation,
+    //centre of the formation in world units
+    pub position: (f32, f32),
+    //degrees counter-clockwise, 0 faces +x
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(default = "default_spacing")]
+    pub spacing: f32,
+}
+
+/// Shape of an army, laid out facing +x before `rotation` is applied.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Formation {
+    Rectangle { columns: u32 },
+    Circle,
+    Line,
+    Wedge,
+    Column { width: u32 },
+}
+
+fn default_spacing() -> f32 {
+    4.0
+}
+
+fn invalid(reason: String) -> ConfigError {
+    ConfigError::Invalid {
+        field: "scenario",
+        reason,
+    }
+}
+
+impl Scenario {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.display().to_string(),
+            error,
+        })?;
+        ron::from_str(&text).map_err(|error| ConfigError::Parse {
+            path: path.display().to_string(),
+            error,
+        })
+    }
+
+    /// Copies the scenario's factions and unit count into the config.
+    pub fn apply(&self, config: &mut SimulationConfig) -> Result<(), ConfigError> {
+        if let Some(factions) = &self.factions {
+            config.factions = factions.clone();
+        }
+        if let Some(alliances) = &self.alliances {
+            config.alliances = alliances.clone();
+        }
+        config.unit_count = u32::try_from(self.unit_count()).map_err(|_| {
+            invalid(format!(
+                "armies add up to {} units, which does not fit in the unit count",
+                self.unit_count()
+            ))
+        })?;
+        Ok(())
+    }
+
+    /// Checks the armies against a config that `apply` has already been called on.
+    pub fn validate(&self, config: &SimulationConfig) -> Result<(), ConfigError> {
+        if self.armies.is_empty() {
+            return Err(invalid("must contain at least one army".to_owned()));
+        }
+        let half_world = Vec2::new(config.world_size.0 as f32, config.world_size.1 as f32) / 2.0;
+        for (index, army) in self.armies.iter().enumerate() {
+            if army.faction as usize >= config.factions.len() {
+                return Err(invalid(format!(
+                    "army {index} uses faction {}, there are {} factions",
+                    army.faction,
+                    config.factions.len()
+                )));
+            }
+            if config.unit_type_index(&army.unit_type).is_none() {
+                return Err(invalid(format!(
+                    "army {index} uses unknown unit type `{}`",
+                    army.unit_type
+                )));
+            }
+            if army.count == 0 {
+                return Err(invalid(format!("army {index} must have at least one unit")));
+            }
+            if !army.spacing.is_finite() || army.spacing <= 0.0 {
+                return Err(invalid(format!(
+                    "army {index} spacing must be a finite number greater than 0"
+                )));
+            }
+            if !army.rotation.is_finite() {
+                return Err(invalid(format!("army {index} rotation must be finite")));
+            }
+            match army.formation {
+                Formation::Rectangle { columns: 0 } | Formation::Column { width: 0 } => {
+                    return Err(invalid(format!(
+                        "army {index} formation must be at least one unit wide"
+                    )));
+                }
+                _ => {}
+            }
+            let position = Vec2::new(army.position.0, army.position.1);
+            if !position.is_finite() || (position.abs() + army.extent()).cmpgt(half_world).any() {
+                return Err(invalid(format!(
+                    "army {index} does not fit inside of the world"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    //summed as u64 so a scenario with too many units is rejected instead of wrapping around
+    pub fn unit_count(&self) -> u64 {
+        self.armies.iter().map(|army| army.count as u64).sum()
+    }
+
+    /// Builds the initial unit list, ids are assigned in army order.
+    pub fn spawn(&self, config: &SimulationConfig, rng: &mut impl Rng) -> Vec<Unit> {
+        let mut units = Vec::with_capacity(config.unit_count as usize);
+        for army in &self.armies {
+            let unit_type = config.unit_type_index(&army.unit_type).unwrap();
+            let health = config.unit_types[unit_type].max_health;
+            let center = Vec2::new(army.position.0, army.position.1);
+            let rotation = Vec2::from_angle(army.rotation.to_radians());
+            //a little jitter keeps units from stacking perfectly, which would make the avoidance divide by zero
+            let jitter = army.spacing * 0.1;
+            for i in 0..army.count {
+                let local = army.formation.offset(i, army.count) * army.spacing;
+                let position = center
+                    + rotation.rotate(local)
+                    + Vec2::new(
+                        rng.gen_range(-jitter..jitter),
+                        rng.gen_range(-jitter..jitter),
+                    );
+                units.push(Unit {
+                    hash_id: -1,
+                    attack_id: -1,
+                    previous_state: position,
+                    current_state: position,
+                    velocity: Vec2::ZERO,
+                    id: units.len() as i32,
+                    health,
+                    faction: army.faction as i32,
+                    unit_type: unit_type as i32,
+                    reload: 0,
+                    morale: 1.0,
+                    routing: 0,
+                });
+            }
+        }
+        units
+    }
+}
+
+impl Army {
+    /// Half the size of the axis aligned box around the rotated formation, jitter included.
+    fn extent(&self) -> Vec2 {
+        let half = self.formation.extent(self.count) * self.spacing;
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let (sin, cos) = (sin.abs(), cos.abs());
+        Vec2::new(cos * half.x + sin * half.y, sin * half.x + cos * half.y)
+            + Vec2::splat(self.spacing * 0.1)
+    }
+}
+
+impl Formation {
+    /// Half the size of the box that `offset` stays in for `count` units, in multiples of the spacing.
+    fn extent(&self, count: u32) -> Vec2 {
+        match *self {
+            Formation::Rectangle { columns } | Formation::Column { width: columns } => {
+                grid_extent(count, columns)
+            }
+            Formation::Line => grid_extent(count, count),
+            Formation::Circle => Vec2::splat(((count - 1) as f32 / PI).sqrt()),
+            Formation::Wedge => Vec2::splat(triangular_row(count - 1) as f32 / 2.0),
+        }
+    }
+
+    /// Position of unit `index` out of `count`, in multiples of the army's spacing.
+    fn offset(&self, index: u32, count: u32) -> Vec2 {
+        match *self {
+            Formation::Rectangle { columns } | Formation::Column { width: columns } => {
+                grid_offset(index, count, columns)
+            }
+            Formation::Line => grid_offset(index, count, count),
+            Formation::Circle => {
+                //sunflower spiral, every unit covers roughly one spacing squared
+                let golden_angle = PI * (3.0 - 5.0_f32.sqrt());
+                let radius = (index as f32 / PI).sqrt();
+                Vec2::from_angle(index as f32 * golden_angle) * radius
+            }
+            Formation::Wedge => {
+                //row r holds r + 1 units, the tip points forward
+                let row = triangular_row(index);
+                let file = index - row * (row + 1) / 2;
+                let rows = triangular_row(count - 1) + 1;
+                Vec2::new(
+                    (rows - 1) as f32 / 2.0 - row as f32,
+                    file as f32 - row as f32 / 2.0,
+                )
+            }
+        }
+    }
+}
+
+//ranks stack backwards along -x, files spread along y, centred on the origin
+fn grid_offset(index: u32, count: u32, columns: u32) -> Vec2 {
+    let columns = columns.min(count);
+    let ranks = count.div_ceil(columns);
+    let rank = index / columns;
+    let file = index % columns;
+    Vec2::new(
+        (ranks - 1) as f32 / 2.0 - rank as f32,
+        file as f32 - (columns - 1) as f32 / 2.0,
+    )
+}
+
+fn grid_extent(count: u32, columns: u32) -> Vec2 {
+    let columns = columns.min(count);
+    let ranks = count.div_ceil(columns);
+    Vec2::new((ranks - 1) as f32, (columns - 1) as f32) / 2.0
+}
+
+//the row r for which r * (r + 1) / 2 <= index < (r + 1) * (r + 2) / 2
+fn triangular_row(index: u32) -> u32 {
+    let index = index as u64;
+    let mut row = ((((8 * index + 1) as f64).sqrt() - 1.0) / 2.0) as u64;
+    while row * (row + 1) / 2 > index {
+        row -= 1;
+    }
+    while (row + 1) * (row + 2) / 2 <= index {
+        row += 1;
+    }
+    row as u32
+}
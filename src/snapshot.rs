@@ -0,0 +1,556 @@
+use std::{fmt, fs, path::Path};
+
+use bevy::{
+    prelude::*,
+    render::{render_resource::*, renderer::RenderQueue, MainWorld, RenderApp},
+};
+
+use crate::{
+    battle::BattleResult,
+    config::{SimulationBackend, SimulationConfig},
+    cpu_sim::simulation::CpuSimulation,
+    faction::FactionCounts,
+    helpers::{
+        bytes::{ByteReader, EndOfFile},
+        helpers::write_buffer,
+    },
+    projectile::Projectile,
+    readback::{
+        FactionReadback, ProjectileReadback, ReadbackRequests, ReadbackState, UnitReadback,
+    },
+    replay::ReplayRecorder,
+    timestep::{clock::SimulationClock, control::SimulationControl},
+    unit::{LiveUnits, Unit, UnitCombat},
+    CombatBuffer, CompactedBuffer, DispatchBuffer, FactionCountsBuffer, LiveBuffer,
+    ProjectileBuffer, ProjectileCounterBuffer, UnitBuffer,
+};
+
+const MAGIC: &[u8; 8] = b"BTLSNAP\0";
+/// Bumped whenever the header or the layout of `Unit` or `Projectile` changes, older files are rejected.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// Everything about the run a snapshot needs to be checked against before it is loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotHeader {
+    pub seed: u64,
+    //tick the units were copied after
+    pub tick: u64,
+    pub unit_count: u32,
+    pub capacity: u32,
+    pub grid_size: i32,
+    pub world_size: (i32, i32),
+    pub unit_type_count: u32,
+    //one entry per faction, living units are counted from the units again
+    pub kills: Vec<u32>,
+    //slots of the projectile buffer, `max_projectiles` of the config
+    pub projectile_count: u32,
+    pub projectile_counter: u32,
+}
+
+/// The state of a battle after a tick: a header, the live part of the unit buffer and the projectile
+/// buffer, stored in the same layout as on the GPU.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub header: SnapshotHeader,
+    pub units: Vec<Unit>,
+    pub projectiles: Vec<Projectile>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io {
+        path: String,
+        error: std::io::Error,
+    },
+    //not a snapshot, a newer version or cut short
+    Format(String),
+    //the snapshot was saved with a config that lays out the buffers differently
+    Mismatch {
+        field: &'static str,
+        saved: String,
+        current: String,
+    },
+    //the unit buffers of the config are smaller than the saved battle
+    TooManyUnits {
+        units: u32,
+        capacity: u32,
+    },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io { path, error } => write!(f, "could not access {path}: {error}"),
+            SnapshotError::Format(reason) => write!(f, "not a valid snapshot: {reason}"),
+            SnapshotError::Mismatch {
+                field,
+                saved,
+                current,
+            } => write!(
+                f,
+                "snapshot does not fit the config: `{field}` is {saved} in the snapshot and {current} here"
+            ),
+            SnapshotError::TooManyUnits { units, capacity } => write!(
+                f,
+                "snapshot does not fit the config: it holds {units} units, the buffers here only have room for {capacity}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+fn mismatch(
+    field: &'static str,
+    saved: impl fmt::Debug,
+    current: impl fmt::Debug,
+) -> SnapshotError {
+    SnapshotError::Mismatch {
+        field,
+        saved: format!("{saved:?}"),
+        current: format!("{current:?}"),
+    }
+}
+
+impl From<EndOfFile> for SnapshotError {
+    fn from(_: EndOfFile) -> Self {
+        SnapshotError::Format("the file ends early".to_owned())
+    }
+}
+
+impl Snapshot {
+    /// Takes the units, faction counts and projectiles read back after `tick`.
+    pub fn new(
+        config: &SimulationConfig,
+        tick: u64,
+        units: Vec<Unit>,
+        counts: &[FactionCounts],
+        projectiles: &ProjectileReadback,
+    ) -> Self {
+        Self {
+            header: SnapshotHeader {
+                seed: config.seed(),
+                tick,
+                unit_count: units.len() as u32,
+                capacity: config.capacity() as u32,
+                grid_size: config.grid_size,
+                world_size: config.world_size,
+                unit_type_count: config.unit_types.len() as u32,
+                kills: counts.iter().map(|counts| counts.kills).collect(),
+                projectile_count: projectiles.projectiles.len() as u32,
+                projectile_counter: projectiles.counter,
+            },
+            units,
+            projectiles: projectiles.projectiles.clone(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = &self.header;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&header.seed.to_le_bytes());
+        bytes.extend_from_slice(&header.tick.to_le_bytes());
+        bytes.extend_from_slice(&header.unit_count.to_le_bytes());
+        bytes.extend_from_slice(&header.capacity.to_le_bytes());
+        bytes.extend_from_slice(&header.grid_size.to_le_bytes());
+        bytes.extend_from_slice(&header.world_size.0.to_le_bytes());
+        bytes.extend_from_slice(&header.world_size.1.to_le_bytes());
+        bytes.extend_from_slice(&header.unit_type_count.to_le_bytes());
+        bytes.extend_from_slice(&(header.kills.len() as u32).to_le_bytes());
+        for kills in &header.kills {
+            bytes.extend_from_slice(&kills.to_le_bytes());
+        }
+        bytes.extend_from_slice(&header.projectile_count.to_le_bytes());
+        bytes.extend_from_slice(&header.projectile_counter.to_le_bytes());
+        let mut buffer = encase::StorageBuffer::new(Vec::new());
+        buffer.write(&self.units).unwrap();
+        bytes.extend_from_slice(&buffer.into_inner());
+        let mut buffer = encase::StorageBuffer::new(Vec::new());
+        buffer.write(&self.projectiles).unwrap();
+        bytes.extend_from_slice(&buffer.into_inner());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::Format(
+                "missing the snapshot marker".to_owned(),
+            ));
+        }
+        let version = reader.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Format(format!(
+                "version {version} can not be read, expected version {SNAPSHOT_VERSION}"
+            )));
+        }
+        let seed = reader.u64()?;
+        let tick = reader.u64()?;
+        let unit_count = reader.u32()?;
+        let capacity = reader.u32()?;
+        let grid_size = reader.i32()?;
+        let world_size = (reader.i32()?, reader.i32()?);
+        let unit_type_count = reader.u32()?;
+        if unit_count > capacity {
+            return Err(SnapshotError::Format(format!(
+                "{unit_count} units do not fit the saved capacity of {capacity}"
+            )));
+        }
+        let faction_count = reader.u32()?;
+        let kills = (0..faction_count)
+            .map(|_| reader.u32())
+            .collect::<Result<Vec<_>, _>>()?;
+        let projectile_count = reader.u32()?;
+        let projectile_counter = reader.u32()?;
+        let size = unit_count as usize * Unit::min_size().get() as usize;
+        let units: Vec<Unit> = encase::StorageBuffer::new(reader.take(size)?)
+            .create()
+            .map_err(|error| SnapshotError::Format(error.to_string()))?;
+        let size = projectile_count as usize * Projectile::min_size().get() as usize;
+        let projectiles: Vec<Projectile> = encase::StorageBuffer::new(reader.take(size)?)
+            .create()
+            .map_err(|error| SnapshotError::Format(error.to_string()))?;
+        if !reader.is_empty() {
+            return Err(SnapshotError::Format(
+                "unexpected bytes after the projectiles".to_owned(),
+            ));
+        }
+        //the faction and unit type index tables on the GPU, an out of range index would read past them
+        if units.iter().any(|unit| {
+            unit.faction as u32 >= faction_count || unit.unit_type as u32 >= unit_type_count
+        }) {
+            return Err(SnapshotError::Format(
+                "a unit has an unknown faction or unit type".to_owned(),
+            ));
+        }
+        if projectiles
+            .iter()
+            .any(|projectile| projectile.faction as u32 >= faction_count)
+        {
+            return Err(SnapshotError::Format(
+                "a projectile has an unknown faction".to_owned(),
+            ));
+        }
+        Ok(Self {
+            header: SnapshotHeader {
+                seed,
+                tick,
+                unit_count,
+                capacity,
+                grid_size,
+                world_size,
+                unit_type_count,
+                kills,
+                projectile_count,
+                projectile_counter,
+            },
+            units,
+            projectiles,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes()).map_err(|error| SnapshotError::Io {
+            path: path.display().to_string(),
+            error,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|error| SnapshotError::Io {
+            path: path.display().to_string(),
+            error,
+        })?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Checks that the units fit the buffers and tables of the running config, the behaviour and
+    /// unit stats may differ.
+    pub fn check(&self, config: &SimulationConfig) -> Result<(), SnapshotError> {
+        let header = &self.header;
+        //the saved capacity may differ, the units are padded to the capacity of this run
+        if header.unit_count > config.capacity() as u32 {
+            return Err(SnapshotError::TooManyUnits {
+                units: header.unit_count,
+                capacity: config.capacity() as u32,
+            });
+        }
+        if header.grid_size != config.grid_size {
+            return Err(mismatch("grid_size", header.grid_size, config.grid_size));
+        }
+        if header.world_size != config.world_size {
+            return Err(mismatch("world_size", header.world_size, config.world_size));
+        }
+        if header.kills.len() != config.factions.len() {
+            return Err(mismatch(
+                "factions",
+                header.kills.len(),
+                config.factions.len(),
+            ));
+        }
+        if header.unit_type_count as usize != config.unit_types.len() {
+            return Err(mismatch(
+                "unit_types",
+                header.unit_type_count,
+                config.unit_types.len(),
+            ));
+        }
+        if header.projectile_count != config.max_projectiles {
+            return Err(mismatch(
+                "max_projectiles",
+                header.projectile_count,
+                config.max_projectiles,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Living units and kills of every faction, like `count_factions` would find them.
+    pub fn faction_counts(&self) -> Vec<FactionCounts> {
+        let mut counts: Vec<FactionCounts> = self
+            .header
+            .kills
+            .iter()
+            .map(|&kills| FactionCounts { alive: 0, kills })
+            .collect();
+        for unit in self.units.iter().filter(|unit| unit.health > 0) {
+            counts[unit.faction as usize].alive += 1;
+        }
+        counts
+    }
+}
+
+/// Quicksaves and quickloads waiting for the state they need.
+#[derive(Resource, Default)]
+pub struct Snapshots {
+    //tick the save was asked for at, it is written once both readbacks have caught up
+    save: Option<u64>,
+    //held back until the clock has started, before that the GPU buffers do not exist
+    load: Option<Snapshot>,
+    //taken by the render world, which overwrites the GPU buffers with it
+    upload: Option<Snapshot>,
+}
+
+impl Snapshots {
+    /// Writes `quicksave_path` once the units and faction counts of `tick` or later have been read back,
+    /// the readback has to be requested separately.
+    pub fn save(&mut self, tick: u64) {
+        self.save = Some(tick);
+    }
+
+    /// Replaces the battle with the snapshot as soon as the simulation is running.
+    pub fn load(&mut self, snapshot: Snapshot) {
+        self.load = Some(snapshot);
+    }
+}
+
+/// F5 saves the battle to `quicksave_path` and F9 loads it back, on either backend.
+pub struct SnapshotPlugin;
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Snapshots>().add_systems(
+            Update,
+            (quick_save_load, save_snapshot, load_snapshot).chain(),
+        );
+        if app.world().resource::<SimulationConfig>().backend == SimulationBackend::Gpu {
+            app.sub_app_mut(RenderApp)
+                .add_systems(ExtractSchedule, upload_snapshot);
+        }
+    }
+}
+
+fn quick_save_load(
+    keys: Res<ButtonInput<KeyCode>>,
+    config: Res<SimulationConfig>,
+    clock: Res<SimulationClock>,
+    mut snapshots: ResMut<Snapshots>,
+    mut requests: ResMut<ReadbackRequests>,
+) {
+    if !clock.is_started() {
+        return;
+    }
+    if keys.just_pressed(KeyCode::F5) {
+        snapshots.save(clock.tick());
+        requests.request();
+    }
+    if keys.just_pressed(KeyCode::F9) {
+        let path = &config.quicksave_path;
+        match Snapshot::load(path).and_then(|snapshot| snapshot.check(&config).map(|()| snapshot)) {
+            Ok(snapshot) => snapshots.load(snapshot),
+            Err(err) => warn!("quickload failed: {err}"),
+        }
+    }
+}
+
+fn save_snapshot(
+    config: Res<SimulationConfig>,
+    clock: Res<SimulationClock>,
+    (unit_readback, faction_readback, projectile_readback): (
+        Res<UnitReadback>,
+        Res<FactionReadback>,
+        Res<ProjectileReadback>,
+    ),
+    mut snapshots: ResMut<Snapshots>,
+    mut requests: ResMut<ReadbackRequests>,
+) {
+    let Some(tick) = snapshots.save else {
+        return;
+    };
+    if unit_readback.tick < tick
+        || faction_readback.tick < tick
+        || projectile_readback.tick < tick
+        || faction_readback.counts.is_empty()
+        || projectile_readback.projectiles.is_empty()
+    {
+        return;
+    }
+    //a periodic readback can land next to the requested one, the units, kills and projectiles have to match
+    if unit_readback.tick != faction_readback.tick || unit_readback.tick != projectile_readback.tick
+    {
+        snapshots.save(clock.tick());
+        requests.request();
+        return;
+    }
+    snapshots.save = None;
+    let snapshot = Snapshot::new(
+        &config,
+        unit_readback.tick,
+        unit_readback.units.clone(),
+        &faction_readback.counts,
+        &projectile_readback,
+    );
+    match snapshot.save(&config.quicksave_path) {
+        Ok(()) => info!(
+            "saved tick {} to {}",
+            snapshot.header.tick,
+            config.quicksave_path.display()
+        ),
+        Err(err) => warn!("quicksave failed: {err}"),
+    }
+}
+
+fn load_snapshot(
+    mut commands: Commands,
+    config: Res<SimulationConfig>,
+    mut snapshots: ResMut<Snapshots>,
+    (mut clock, mut fixed, mut control): (
+        ResMut<SimulationClock>,
+        ResMut<Time<Fixed>>,
+        ResMut<SimulationControl>,
+    ),
+    (mut unit_readback, mut faction_readback): (ResMut<UnitReadback>, ResMut<FactionReadback>),
+    (simulation, recorder): (Option<ResMut<CpuSimulation>>, Option<Res<ReplayRecorder>>),
+    result: Option<Res<BattleResult>>,
+) {
+    if !clock.is_started() {
+        return;
+    }
+    let Some(snapshot) = snapshots.load.take() else {
+        return;
+    };
+    let tick = snapshot.header.tick;
+    //replay frames follow each other tick by tick, the record ends where the loaded battle takes over
+    if recorder.is_some() {
+        warn!(
+            "stopped recording the replay at tick {}, it can not follow a quickload",
+            clock.tick()
+        );
+        commands.remove_resource::<ReplayRecorder>();
+    }
+    //the ticks counted this frame belong to the replaced battle, the next one starts on a whole tick
+    clock.restore(tick);
+    let overstep = fixed.overstep();
+    fixed.discard_overstep(overstep);
+
+    //the readbacks would describe the replaced battle until the next copy arrives
+    *unit_readback = UnitReadback {
+        tick,
+        live: LiveUnits::new(
+            snapshot.units.len() as u32,
+            config.capacity() as u32,
+            config.workgroup_size,
+        ),
+        units: snapshot.units.clone(),
+    };
+    *faction_readback = FactionReadback {
+        tick,
+        counts: snapshot.faction_counts(),
+    };
+    //a battle that already ended may be loaded from before its end, which also undoes `victory.on_end`
+    if let Some(result) = result {
+        control.restore(&result.control);
+        commands.remove_resource::<BattleResult>();
+    }
+    info!("loaded tick {tick}");
+    match simulation {
+        Some(mut simulation) => simulation.restore(
+            &snapshot.units,
+            &snapshot.header.kills,
+            &snapshot.projectiles,
+            snapshot.header.projectile_counter,
+        ),
+        None => snapshots.upload = Some(snapshot),
+    }
+}
+
+//overwrites everything a tick carries over with the loaded battle, the cells are rebuilt by the next tick
+fn upload_snapshot(world: &mut World) {
+    let Some(snapshot) = world
+        .resource_mut::<MainWorld>()
+        .resource_mut::<Snapshots>()
+        .upload
+        .take()
+    else {
+        return;
+    };
+    let config = world.resource::<SimulationConfig>();
+    let render_queue = world.resource::<RenderQueue>();
+    let capacity = config.capacity() as usize;
+    let live = LiveUnits::new(
+        snapshot.units.len() as u32,
+        capacity as u32,
+        config.workgroup_size,
+    );
+    let mut units = snapshot.units.clone();
+    units.resize(capacity, Unit::sentinel());
+
+    write_buffer(render_queue, &world.resource::<UnitBuffer>().0[0], &units);
+    //the compaction only writes the live units, so the padding behind them has to be there already
+    write_buffer(
+        render_queue,
+        &world.resource::<CompactedBuffer>().0[0],
+        &units,
+    );
+    write_buffer(render_queue, &world.resource::<LiveBuffer>().0[0], &live);
+    write_buffer(
+        render_queue,
+        &world.resource::<DispatchBuffer>().0[0],
+        &live,
+    );
+    write_buffer(
+        render_queue,
+        &world.resource::<CombatBuffer>().0[0],
+        &vec![UnitCombat::default(); capacity],
+    );
+    write_buffer(
+        render_queue,
+        &world.resource::<ProjectileBuffer>().0[0],
+        &snapshot.projectiles,
+    );
+    write_buffer(
+        render_queue,
+        &world.resource::<ProjectileCounterBuffer>().0[0],
+        &snapshot.header.projectile_counter,
+    );
+    write_buffer(
+        render_queue,
+        &world.resource::<FactionCountsBuffer>().0[0],
+        &snapshot.faction_counts(),
+    );
+    world
+        .resource_mut::<ReadbackState>()
+        .set_live_count(live.count);
+}
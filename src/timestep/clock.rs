@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use bevy::{
+    prelude::*,
+    render::extract_resource::{ExtractResource, ExtractResourcePlugin},
+    time::TimeSystem,
+};
+
+use super::control::SimulationControl;
+use crate::config::{SimulationConfig, WindowMode};
+
+//longest frame that is fed into the fixed timestep, so a stall does not turn into a burst of ticks
+const MAX_FRAME_TIME: f32 = 0.1;
+
+/// The authoritative simulation clock, advanced in the main world by `Time<Fixed>` and extracted for the logic node.
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct SimulationClock {
+    //ticks completed once this frame's ticks have run on the GPU
+    tick: u64,
+    //ticks the logic node runs this frame
+    ticks: u32,
+    timestep: f32,
+    //how far rendering is between the previous and the latest tick
+    alpha: f32,
+    //single steps of the control that have already been run
+    steps_taken: u64,
+    //set once the logic pipelines are compiled, ticks before that would never reach the GPU
+    started: bool,
+    //the clock stops here so the time limit is hit exactly
+    stop_at: Option<u64>,
+}
+
+impl SimulationClock {
+    pub fn new(timestep: f32, stop_at: Option<u64>) -> Self {
+        Self {
+            timestep,
+            stop_at,
+            ..default()
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    pub fn start(&mut self) {
+        self.started = true;
+    }
+
+    /// Jumps to a saved tick, the ticks already counted this frame are dropped.
+    pub fn restore(&mut self, tick: u64) {
+        self.tick = tick;
+        self.ticks = 0;
+        self.alpha = 0.0;
+    }
+
+    /// Moves back `ticks` ticks without resetting `alpha`, which is how a replay plays in reverse.
+    pub fn rewind(&mut self, ticks: u64) {
+        self.tick -= ticks;
+        self.ticks = 0;
+    }
+
+    /// Moves the tick the clock stops at, `None` runs on forever.
+    pub fn set_stop_at(&mut self, stop_at: Option<u64>) {
+        self.stop_at = stop_at;
+    }
+
+    //whether another tick may run
+    fn can_tick(&self) -> bool {
+        self.started && self.stop_at.is_none_or(|stop_at| self.tick < stop_at)
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// The ticks that run this frame, in order.
+    pub fn frame_ticks(&self) -> std::ops::RangeInclusive<u64> {
+        self.tick + 1 - self.ticks as u64..=self.tick
+    }
+
+    /// Simulated seconds since the start of the battle.
+    pub fn time(&self) -> f32 {
+        self.tick as f32 * self.timestep
+    }
+
+    pub fn timestep(&self) -> f32 {
+        self.timestep
+    }
+
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+}
+
+pub struct SimulationClockPlugin;
+impl Plugin for SimulationClockPlugin {
+    fn build(&self, app: &mut App) {
+        let config = app.world().resource::<SimulationConfig>();
+        let clock = SimulationClock::new(1.0 / config.tick_rate, config.victory.time_limit);
+        app.insert_resource(Time::<Fixed>::from_hz(config.tick_rate as f64))
+            .insert_resource(clock)
+            .add_plugins(ExtractResourcePlugin::<SimulationClock>::default())
+            .add_systems(First, (apply_control.before(TimeSystem), begin_frame))
+            .add_systems(FixedUpdate, count_tick)
+            .add_systems(
+                RunFixedMainLoop,
+                finish_frame.in_set(RunFixedMainLoopSystem::AfterFixedMainLoop),
+            );
+    }
+}
+
+//pausing and speed go through virtual time, the frame cap keeps the fixed loop under max_ticks_per_frame
+fn apply_control(
+    control: Res<SimulationControl>,
+    config: Res<SimulationConfig>,
+    mut time: ResMut<Time<Virtual>>,
+) {
+    if control.is_paused() {
+        time.pause();
+    } else {
+        time.unpause();
+    }
+    time.set_relative_speed(control.speed());
+    let cap = config.max_ticks_per_frame as f32 / (config.tick_rate * control.speed());
+    //headless frames advance by a fixed amount instead of the wall clock, so there are no stalls to guard against
+    let cap = match config.window_mode {
+        WindowMode::Headless => cap,
+        _ => cap.min(MAX_FRAME_TIME),
+    };
+    time.set_max_delta(Duration::from_secs_f32(cap));
+}
+
+fn begin_frame(mut clock: ResMut<SimulationClock>) {
+    clock.ticks = 0;
+}
+
+fn count_tick(mut clock: ResMut<SimulationClock>) {
+    if clock.can_tick() {
+        clock.tick += 1;
+        clock.ticks += 1;
+    }
+}
+
+fn finish_frame(
+    control: Res<SimulationControl>,
+    config: Res<SimulationConfig>,
+    fixed: Res<Time<Fixed>>,
+    mut clock: ResMut<SimulationClock>,
+) {
+    if control.is_paused() {
+        //the fixed loop does not run while virtual time is paused, so single steps are counted here
+        let steps = control.steps().saturating_sub(clock.steps_taken);
+        let steps = steps.min(config.max_ticks_per_frame as u64);
+        clock.steps_taken += steps;
+        for _ in 0..steps {
+            if clock.can_tick() {
+                clock.tick += 1;
+                clock.ticks += 1;
+            }
+        }
+    } else {
+        clock.steps_taken = control.steps();
+    }
+    clock.alpha = fixed.overstep_fraction();
+}
@@ -0,0 +1,98 @@
+use bevy::prelude::*;
+
+pub const MIN_SPEED: f32 = 0.25;
+pub const MAX_SPEED: f32 = 8.0;
+
+/// Pause, single step and speed state of the simulation, set from the main world and applied to virtual time by the simulation clock.
+#[derive(Resource, Clone, Debug)]
+pub struct SimulationControl {
+    paused: bool,
+    speed: f32,
+    //total single steps requested so far, the clock runs one tick for every step it has not seen yet
+    steps: u64,
+}
+
+impl Default for SimulationControl {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            speed: 1.0,
+            steps: 0,
+        }
+    }
+}
+
+impl SimulationControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// Advances exactly one tick, ignored while the simulation is running.
+    pub fn step(&mut self) {
+        if self.paused {
+            self.steps += 1;
+        }
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Sets the speed multiplier, clamped to `MIN_SPEED..=MAX_SPEED`.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /// Takes over the pause state and speed of `other`, single steps already requested still run.
+    pub fn restore(&mut self, other: &SimulationControl) {
+        self.paused = other.paused;
+        self.speed = other.speed;
+    }
+
+    pub fn faster(&mut self) {
+        self.set_speed(self.speed * 2.0);
+    }
+
+    pub fn slower(&mut self) {
+        self.set_speed(self.speed / 2.0);
+    }
+}
+
+pub struct SimulationControlPlugin;
+impl Plugin for SimulationControlPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimulationControl>()
+            .add_systems(Update, update_control);
+    }
+}
+
+fn update_control(keys: Res<ButtonInput<KeyCode>>, mut control: ResMut<SimulationControl>) {
+    if keys.just_pressed(KeyCode::Space) {
+        control.toggle_pause();
+    }
+    if keys.just_pressed(KeyCode::Period) {
+        control.step();
+    }
+    if keys.just_pressed(KeyCode::BracketRight) {
+        control.faster();
+    }
+    if keys.just_pressed(KeyCode::BracketLeft) {
+        control.slower();
+    }
+}
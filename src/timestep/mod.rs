@@ -1 +1,2 @@
-pub mod fixed_time;
+pub mod clock;
+pub mod control;
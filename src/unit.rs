@@ -8,4 +8,80 @@ pub struct Unit {
     pub attack_id: i32,
     pub id: i32,
     pub health: i32,
+    pub faction: i32,
+    pub unit_type: i32,
+    //ticks until a ranged unit can fire again
+    pub reload: i32,
+    //between 0 and 1, the unit routs once it drops below the rout threshold
+    pub morale: f32,
+    //1 while fleeing, 0 otherwise
+    pub routing: i32,
+}
+
+/// Per unit slot accumulators written atomically during a tick and cleared by `apply_damage`.
+#[derive(ShaderType, Clone, Copy)]
+pub struct UnitCombat {
+    pub damage: i32,
+    //lowest id of the units that attacked this slot, -1 when unclaimed
+    pub attacker: i32,
+    //bit per faction that damaged this slot this tick, a kill goes to the lowest of them
+    pub hits: u32,
+}
+
+impl Default for UnitCombat {
+    fn default() -> Self {
+        Self {
+            damage: 0,
+            attacker: -1,
+            hits: 0,
+        }
+    }
+}
+
+/// Indirect dispatch arguments for the per unit passes and the sort, rewritten on the GPU by every compaction.
+#[derive(ShaderType, Default, Clone, Copy)]
+pub struct LiveUnits {
+    pub dispatch_x: u32,
+    pub dispatch_y: u32,
+    pub dispatch_z: u32,
+    //units in front of the padding, dead units stay counted until the next compaction
+    pub count: u32,
+    pub sort_x: u32,
+    pub sort_y: u32,
+    pub sort_z: u32,
+    pub next_count: u32,
+}
+
+impl LiveUnits {
+    //byte offsets of the two sets of dispatch arguments
+    pub const DISPATCH_OFFSET: u64 = 0;
+    pub const SORT_OFFSET: u64 = 16;
+
+    pub fn new(count: u32, capacity: u32, workgroup_size: u32) -> Self {
+        Self {
+            dispatch_x: count.div_ceil(workgroup_size),
+            dispatch_y: 1,
+            dispatch_z: 1,
+            count,
+            sort_x: capacity / (2 * workgroup_size),
+            sort_y: 1,
+            sort_z: 1,
+            next_count: count,
+        }
+    }
+}
+
+//sorts behind every real hash id so padding always ends up at the back of the unit buffer
+pub const SENTINEL_HASH: i32 = i32::MAX;
+
+impl Unit {
+    /// A dead placeholder used to pad the unit buffer up to the sort size.
+    pub fn sentinel() -> Self {
+        Self {
+            hash_id: SENTINEL_HASH,
+            attack_id: -1,
+            id: -1,
+            ..Default::default()
+        }
+    }
 }
@@ -0,0 +1,46 @@
+use bevy::render::render_resource::ShaderType;
+
+use crate::config::SimulationConfig;
+
+/// Per-type stats, indexed on the GPU by `Unit::unit_type`.
+#[derive(ShaderType, Default, Clone, Copy)]
+pub struct UnitTypeStats {
+    pub max_health: i32,
+    pub melee_damage: i32,
+    pub speed: f32,
+    pub attack_range: f32,
+    pub protected_range: f32,
+    pub mass: f32,
+    //0 for melee only units
+    pub fire_range: f32,
+    pub projectile_speed: f32,
+    pub projectile_damage: i32,
+    pub reload_ticks: i32,
+    pub splash_radius: f32,
+}
+
+pub fn unit_type_table(config: &SimulationConfig) -> Vec<UnitTypeStats> {
+    config
+        .unit_types
+        .iter()
+        .map(|unit_type| {
+            let mut stats = UnitTypeStats {
+                max_health: unit_type.max_health,
+                melee_damage: unit_type.melee_damage,
+                speed: unit_type.speed,
+                attack_range: unit_type.attack_range,
+                protected_range: unit_type.protected_range,
+                mass: unit_type.mass,
+                ..Default::default()
+            };
+            if let Some(ranged) = &unit_type.ranged {
+                stats.fire_range = ranged.fire_range;
+                stats.projectile_speed = ranged.projectile_speed;
+                stats.projectile_damage = ranged.damage;
+                stats.reload_ticks = ranged.reload_ticks;
+                stats.splash_radius = ranged.splash_radius;
+            }
+            stats
+        })
+        .collect()
+}
@@ -0,0 +1,515 @@
+//! Runs a small battle on the GPU and on the CPU reference side by side and checks that they agree.
+//!
+//! The GPU half needs a Vulkan, Metal or DX12 adapter, a software one like lavapipe is enough. It is
+//! ignored by default, run it with `cargo test -- --ignored` on a machine that has one.
+
+use std::time::{Duration, Instant};
+
+use bevy::{
+    app::PluginsState,
+    log::LogPlugin,
+    prelude::*,
+    render::{
+        pipelined_rendering::PipelinedRenderingPlugin,
+        render_resource::{encase, *},
+        renderer::{RenderAdapterInfo, RenderContext, RenderDevice, RenderQueue},
+        settings::{Backends, WgpuSettings},
+        RenderApp, RenderPlugin,
+    },
+    tasks::{block_on, tick_global_task_pools_on_main_thread},
+    time::TimeUpdateStrategy,
+    winit::WinitPlugin,
+};
+use compute_shaders::{
+    config::{BoundaryMode, SimulationConfig, SortBackend, WindowMode},
+    cpu_sim::simulation::CpuSimulation,
+    grid::CellRange,
+    logic::{LogicBindGroup, LogicNode, LogicPass},
+    scenario::{Army, Formation, Scenario},
+    timestep::{clock::SimulationClock, control::SimulationControl},
+    unit::{LiveUnits, Unit},
+    CellBuffer, LiveBuffer, SimulationPlugin, SimulationUniforms, UniformData, UnitBuffer,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+const TICKS: u64 = 24;
+//both sides compact on the same ticks, a short interval makes sure a few compactions are covered
+const COMPACTION_INTERVAL: u32 = 8;
+//world units, the GPU may fuse or reorder float operations the CPU does one by one
+const POSITION_TOLERANCE: f32 = 1e-2;
+//frames the GPU app gets to compile its pipelines and finish the run
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Two armies a few cells apart, with archers and artillery behind them so projectiles are covered too.
+fn test_battle() -> (SimulationConfig, Scenario) {
+    let mut config = SimulationConfig::load("assets/config.ron").unwrap();
+    config.world_size = (320, 160);
+    config.workgroup_size = 64;
+    config.window_mode = WindowMode::Headless;
+    config.max_ticks_per_frame = 1;
+    config.max_projectiles = 256;
+    config.compaction_interval = COMPACTION_INTERVAL;
+    config.readback_interval = 0;
+    config.seed = Some(7);
+    config.victory.time_limit = Some(TICKS);
+    let army = |faction, unit_type: &str, count, columns, x: f32| Army {
+        faction,
+        unit_type: unit_type.to_owned(),
+        count,
+        formation: Formation::Rectangle { columns },
+        position: (x, 0.0),
+        rotation: if faction == 0 { 0.0 } else { 180.0 },
+        spacing: 3.0,
+    };
+    let scenario = Scenario {
+        name: "Cross validation".to_owned(),
+        factions: None,
+        alliances: None,
+        armies: vec![
+            army(0, "Infantry", 96, 8, -14.0),
+            army(0, "Archer", 32, 8, -36.0),
+            army(1, "Spearman", 96, 8, 14.0),
+            army(1, "Artillery", 16, 4, 36.0),
+        ],
+    };
+    scenario.apply(&mut config).unwrap();
+    config.validate().unwrap();
+    scenario.validate(&config).unwrap();
+    (config, scenario)
+}
+
+fn cpu_simulation(config: &SimulationConfig, scenario: &Scenario) -> CpuSimulation {
+    let mut rng = StdRng::seed_from_u64(config.seed());
+    let units = scenario.spawn(config, &mut rng);
+    CpuSimulation::new(config, &units, UniformData::new(config))
+}
+
+/// Living units must be ordered by cell, dead and padding units may only come before or after them.
+fn assert_sorted(units: &[Unit], context: &str) {
+    for (index, pair) in units.windows(2).enumerate() {
+        assert!(
+            pair[0].hash_id <= pair[1].hash_id,
+            "{context}: hash ids decrease at unit {index}, {} > {}",
+            pair[0].hash_id,
+            pair[1].hash_id,
+        );
+    }
+}
+
+/// Every cell must span exactly the run of units with its hash id, empty cells must be empty.
+fn assert_cells_match(units: &[Unit], cells: &[CellRange], context: &str) {
+    for (cell, range) in cells.iter().enumerate() {
+        let (start, end) = (range.start as usize, range.end as usize);
+        let members = units
+            .iter()
+            .filter(|unit| unit.hash_id == cell as i32)
+            .count();
+        assert_eq!(
+            end - start,
+            members,
+            "{context}: cell {cell} has the wrong length"
+        );
+        if members == 0 {
+            continue;
+        }
+        assert_eq!(
+            units[start].hash_id, cell as i32,
+            "{context}: cell {cell} does not point at one of its units"
+        );
+        assert!(
+            start == 0 || units[start - 1].hash_id != cell as i32,
+            "{context}: cell {cell} does not point at its first unit"
+        );
+        assert_eq!(
+            units[end - 1].hash_id,
+            cell as i32,
+            "{context}: cell {cell} ends early"
+        );
+    }
+}
+
+fn health_per_faction(units: &[Unit], faction_count: usize) -> Vec<i64> {
+    let mut totals = vec![0; faction_count];
+    for unit in units.iter().filter(|unit| unit.health > 0) {
+        totals[unit.faction as usize] += unit.health as i64;
+    }
+    totals
+}
+
+#[test]
+fn cpu_passes_keep_the_cell_invariants() {
+    let (config, scenario) = test_battle();
+    let mut simulation = cpu_simulation(&config, &scenario);
+    for tick in 1..=TICKS {
+        let context = format!("cpu tick {tick}");
+        simulation.hash();
+        simulation.sort();
+        assert_sorted(simulation.units(), &context);
+        simulation.hash_indices();
+        assert_cells_match(simulation.units(), simulation.cells(), &context);
+        //none of the remaining passes move units between slots or change their cell
+        simulation.update();
+        simulation.update_projectiles();
+        simulation.apply_damage();
+        assert_sorted(simulation.units(), &context);
+        assert_cells_match(simulation.units(), simulation.cells(), &context);
+        if tick.is_multiple_of(COMPACTION_INTERVAL as u64) {
+            simulation.compact();
+            assert_sorted(simulation.units(), &context);
+        }
+    }
+    let spawned = scenario.unit_count() as usize;
+    assert!(
+        simulation.units().len() < spawned,
+        "no unit died in {TICKS} ticks, the battle does not test any combat"
+    );
+}
+
+#[test]
+fn units_on_the_far_edge_stay_in_the_grid() {
+    let (mut config, _) = test_battle();
+    config.boundary = BoundaryMode::Clamp;
+    let data = UniformData::new(&config);
+    let half_world = Vec2::new(config.world_size.0 as f32, config.world_size.1 as f32) / 2.0;
+    let unit = |id, faction, position| Unit {
+        hash_id: -1,
+        attack_id: -1,
+        previous_state: position,
+        current_state: position,
+        velocity: Vec2::ZERO,
+        id,
+        health: config.unit_types[0].max_health,
+        faction,
+        unit_type: 0,
+        reload: 0,
+        morale: 1.0,
+        routing: 0,
+    };
+    //pinned to the +x and the +y edge, each with an enemy right next to it
+    let units = [
+        unit(0, 0, Vec2::new(half_world.x, 0.0)),
+        unit(1, 1, Vec2::new(half_world.x - 1.0, 0.0)),
+        unit(2, 0, Vec2::new(0.0, half_world.y)),
+        unit(3, 1, Vec2::new(0.0, half_world.y - 1.0)),
+    ];
+    let mut simulation = CpuSimulation::new(&config, &units, data.clone());
+    simulation.hash();
+    simulation.sort();
+    simulation.hash_indices();
+    assert_cells_match(simulation.units(), simulation.cells(), "edge");
+    let cell = |x: i32, y: i32| x + y * data.grid_width;
+    let hash_id = |id| {
+        simulation
+            .units()
+            .iter()
+            .find(|unit| unit.id == id)
+            .unwrap()
+            .hash_id
+    };
+    assert_eq!(hash_id(0), cell(data.grid_width - 1, data.grid_height / 2));
+    assert_eq!(hash_id(2), cell(data.grid_width / 2, data.grid_height - 1));
+
+    simulation.update();
+    let attack_id = |id| {
+        simulation
+            .units()
+            .iter()
+            .find(|unit| unit.id == id)
+            .unwrap()
+            .attack_id
+    };
+    assert_eq!(attack_id(1), 0, "the unit on the +x edge is invisible");
+    assert_eq!(attack_id(3), 2, "the unit on the +y edge is invisible");
+}
+
+#[test]
+fn cpu_results_do_not_depend_on_the_thread_count() {
+    let (config, scenario) = test_battle();
+    let run = |threads| {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            let mut simulation = cpu_simulation(&config, &scenario);
+            for tick in 1..=TICKS {
+                simulation.step(tick.is_multiple_of(COMPACTION_INTERVAL as u64));
+            }
+            simulation
+        })
+    };
+    let single = run(1);
+    let parallel = run(4);
+    assert_eq!(single.units().len(), parallel.units().len());
+    for (a, b) in single.units().iter().zip(parallel.units()) {
+        assert_eq!(a.id, b.id);
+        assert_eq!(a.health, b.health);
+        assert_eq!(a.current_state, b.current_state);
+    }
+    let kills = |simulation: &CpuSimulation| {
+        simulation
+            .faction_counts()
+            .iter()
+            .map(|counts| (counts.alive, counts.kills))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(kills(&single), kills(&parallel));
+}
+
+//the backends the GPU half may use, GL is left out because its shader backend can not compile logic.wgsl
+const GPU_BACKENDS: Backends = Backends::PRIMARY;
+
+//fails up front instead of letting bevy panic somewhere inside of the render plugin
+fn require_gpu() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: GPU_BACKENDS,
+        ..Default::default()
+    });
+    let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()));
+    assert!(
+        adapter.is_some(),
+        "the GPU cross validation needs a Vulkan, Metal or DX12 adapter"
+    );
+}
+
+fn gpu_app(config: SimulationConfig, scenario: Scenario) -> App {
+    let timestep = Duration::from_secs_f32(1.0 / config.tick_rate);
+    let mut app = App::new();
+    app.insert_resource(config)
+        .insert_resource(scenario)
+        .insert_resource(TimeUpdateStrategy::ManualDuration(timestep))
+        .add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: bevy::window::ExitCondition::DontExit,
+                    ..default()
+                })
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: Some(GPU_BACKENDS),
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                //the test reads the render world between frames, so it has to stay on this thread
+                .disable::<PipelinedRenderingPlugin>()
+                .disable::<WinitPlugin>()
+                .disable::<LogPlugin>(),
+        )
+        .add_plugins(SimulationPlugin);
+    while app.plugins_state() == PluginsState::Adding {
+        tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+    app
+}
+
+//copies a buffer into a mappable one and waits for it
+fn read_buffer(world: &World, buffer: &Buffer) -> Vec<u8> {
+    let render_device = world.resource::<RenderDevice>();
+    let render_queue = world.resource::<RenderQueue>();
+    let staging = render_device.create_buffer(&BufferDescriptor {
+        label: Some("Test Readback Buffer"),
+        size: buffer.size(),
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor::default());
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+    render_queue.submit([encoder.finish()]);
+    staging
+        .slice(..)
+        .map_async(MapMode::Read, |result| result.unwrap());
+    render_device.poll(Maintain::Wait);
+    let data = staging.slice(..).get_mapped_range().to_vec();
+    staging.unmap();
+    data
+}
+
+struct GpuState {
+    units: Vec<Unit>,
+    cells: Vec<CellRange>,
+}
+
+fn read_gpu_state(app: &App) -> GpuState {
+    let world = app.sub_app(RenderApp).world();
+    let data = read_buffer(world, &world.resource::<LiveBuffer>()[0]);
+    let live: LiveUnits = encase::StorageBuffer::new(&data).create().unwrap();
+    let data = read_buffer(world, &world.resource::<UnitBuffer>()[0]);
+    let mut units: Vec<Unit> = encase::StorageBuffer::new(&data).create().unwrap();
+    units.truncate(live.count as usize);
+    let data = read_buffer(world, &world.resource::<CellBuffer>()[0]);
+    let cells: Vec<CellRange> = encase::StorageBuffer::new(&data).create().unwrap();
+    GpuState { units, cells }
+}
+
+/// Compares the units one by one, by id since the sorts may order a cell differently.
+fn compare_units(gpu: &GpuState, cpu: &CpuSimulation, config: &SimulationConfig, context: &str) {
+    let cpu_units = cpu.units();
+    assert_eq!(
+        gpu.units.len(),
+        cpu_units.len(),
+        "{context}: live counts differ"
+    );
+    let mut gpu_by_id = gpu.units.clone();
+    gpu_by_id.sort_by_key(|unit| unit.id);
+    let mut cpu_by_id = cpu_units.to_vec();
+    cpu_by_id.sort_by_key(|unit| unit.id);
+    for (gpu_unit, cpu_unit) in gpu_by_id.iter().zip(&cpu_by_id) {
+        assert_eq!(
+            gpu_unit.id, cpu_unit.id,
+            "{context}: different units are alive"
+        );
+        assert_eq!(
+            gpu_unit.hash_id, cpu_unit.hash_id,
+            "{context}: unit {} is in a different cell",
+            gpu_unit.id
+        );
+        let distance = gpu_unit.current_state.distance(cpu_unit.current_state);
+        assert!(
+            distance <= POSITION_TOLERANCE,
+            "{context}: unit {} is {distance} apart, gpu {} cpu {}",
+            gpu_unit.id,
+            gpu_unit.current_state,
+            cpu_unit.current_state,
+        );
+        assert_eq!(
+            gpu_unit.health, cpu_unit.health,
+            "{context}: unit {} health differs",
+            gpu_unit.id
+        );
+    }
+    let faction_count = config.factions.len();
+    assert_eq!(
+        health_per_faction(&gpu.units, faction_count),
+        health_per_faction(cpu_units, faction_count),
+        "{context}: health totals differ"
+    );
+}
+
+/// Checks the GPU units are sorted and their cells come in the same order as on the CPU.
+fn compare_order(gpu: &GpuState, cpu: &CpuSimulation, config: &SimulationConfig, context: &str) {
+    assert_sorted(&gpu.units, &format!("gpu {context}"));
+    let gpu_hashes: Vec<i32> = gpu.units.iter().map(|unit| unit.hash_id).collect();
+    let cpu_hashes: Vec<i32> = cpu.units().iter().map(|unit| unit.hash_id).collect();
+    assert_eq!(gpu_hashes, cpu_hashes, "{context}: sort orders differ");
+    //only the counting sort also orders the units inside a cell
+    if config.sort_backend == SortBackend::Counting {
+        let placed_ids = |units: &[Unit]| {
+            units
+                .iter()
+                .filter(|unit| unit.hash_id >= 0)
+                .map(|unit| unit.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            placed_ids(&gpu.units),
+            placed_ids(cpu.units()),
+            "{context}: units inside a cell are ordered differently"
+        );
+    }
+}
+
+/// Checks the GPU cell table against its own units and against the CPU one.
+fn compare_cells(gpu: &GpuState, cpu: &CpuSimulation, context: &str) {
+    assert_cells_match(&gpu.units, &gpu.cells, &format!("gpu {context}"));
+    for (cell, (gpu_range, cpu_range)) in gpu.cells.iter().zip(cpu.cells()).enumerate() {
+        assert_eq!(
+            (gpu_range.start, gpu_range.end),
+            (cpu_range.start, cpu_range.end),
+            "{context}: cell {cell} differs"
+        );
+    }
+}
+
+//records a single pass on the GPU and waits for it to finish
+fn run_gpu_pass(app: &App, pass: LogicPass) {
+    let world = app.sub_app(RenderApp).world();
+    let render_device = world.resource::<RenderDevice>();
+    let adapter_info = world.resource::<RenderAdapterInfo>();
+    let mut render_context =
+        RenderContext::new(render_device.clone(), (*adapter_info.0).clone(), None);
+    LogicNode::default().run_pass(pass, &mut render_context, world);
+    let (command_buffers, _, _) = render_context.finish();
+    world.resource::<RenderQueue>().submit(command_buffers);
+    render_device.poll(Maintain::Wait);
+}
+
+fn run_cpu_pass(cpu: &mut CpuSimulation, config: &SimulationConfig, pass: LogicPass) {
+    match pass {
+        LogicPass::Hash => cpu.hash(),
+        LogicPass::Sort => {
+            cpu.sort();
+            //the counting sort builds the cell table on the way
+            if config.sort_backend == SortBackend::Counting {
+                cpu.hash_indices();
+            }
+        }
+        LogicPass::HashIndices => cpu.hash_indices(),
+        LogicPass::Update => cpu.update(),
+        LogicPass::Projectiles => cpu.update_projectiles(),
+        LogicPass::ApplyDamage => cpu.apply_damage(),
+        LogicPass::Compact => cpu.compact(),
+    }
+}
+
+/// Steps both sides pass by pass and compares them after every one of them.
+fn cross_validate(sort_backend: SortBackend) {
+    let (mut config, scenario) = test_battle();
+    config.sort_backend = sort_backend;
+    let mut app = gpu_app(config.clone(), scenario);
+    //the node must not run ticks of its own, the test records every pass itself
+    app.world_mut().resource_mut::<SimulationControl>().pause();
+    let started = Instant::now();
+    while !app.world().resource::<SimulationClock>().is_started()
+        || !app
+            .sub_app(RenderApp)
+            .world()
+            .contains_resource::<LogicBindGroup>()
+    {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "the GPU pipelines did not compile in {TIMEOUT:?}"
+        );
+        app.update();
+    }
+
+    //the units the app spawned, so both sides start from the same state
+    let mut cpu =
+        CpuSimulation::from_uniforms(&config, app.world().resource::<SimulationUniforms>());
+    for tick in 1..=TICKS {
+        let compact = tick.is_multiple_of(COMPACTION_INTERVAL as u64);
+        //the cell table is only valid once it was built from the sorted units and until a compaction moves them
+        let mut cells_built = false;
+        for pass in LogicPass::tick(&config, compact) {
+            run_gpu_pass(&app, pass);
+            run_cpu_pass(&mut cpu, &config, pass);
+            cells_built = match pass {
+                LogicPass::Hash | LogicPass::Compact => false,
+                LogicPass::Sort => sort_backend == SortBackend::Counting,
+                _ => cells_built || pass == LogicPass::HashIndices,
+            };
+
+            let context = format!("{sort_backend:?} sort, tick {tick}, after {pass:?}");
+            let gpu = read_gpu_state(&app);
+            compare_units(&gpu, &cpu, &config, &context);
+            if pass != LogicPass::Hash {
+                compare_order(&gpu, &cpu, &config, &context);
+            }
+            if cells_built {
+                compare_cells(&gpu, &cpu, &context);
+            }
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan, Metal or DX12 adapter, run with `cargo test -- --ignored`"]
+fn gpu_matches_cpu_reference() {
+    require_gpu();
+    cross_validate(SortBackend::Counting);
+    cross_validate(SortBackend::Bitonic);
+}
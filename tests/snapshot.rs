@@ -0,0 +1,189 @@
+//! Writes snapshots of a short CPU battle and reads them back.
+
+use compute_shaders::{
+    config::SimulationConfig,
+    cpu_sim::simulation::CpuSimulation,
+    readback::ProjectileReadback,
+    scenario::{Army, Formation, Scenario},
+    snapshot::{Snapshot, SnapshotError, SNAPSHOT_VERSION},
+    UniformData,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+const SAVED_TICK: u64 = 20;
+const COMPACTION_INTERVAL: u64 = 8;
+
+/// Archers and artillery facing infantry, so there are projectiles in the air when the snapshot is taken.
+fn battle() -> (SimulationConfig, CpuSimulation) {
+    let mut config = SimulationConfig::load("assets/config.ron").unwrap();
+    config.world_size = (320, 160);
+    config.max_projectiles = 256;
+    config.seed = Some(11);
+    let army = |faction, unit_type: &str, count, x: f32| Army {
+        faction,
+        unit_type: unit_type.to_owned(),
+        count,
+        formation: Formation::Rectangle { columns: 8 },
+        position: (x, 0.0),
+        rotation: if faction == 0 { 0.0 } else { 180.0 },
+        spacing: 3.0,
+    };
+    let scenario = Scenario {
+        name: "Snapshot".to_owned(),
+        factions: None,
+        alliances: None,
+        armies: vec![
+            army(0, "Archer", 64, -30.0),
+            army(0, "Infantry", 64, -10.0),
+            army(1, "Artillery", 32, 30.0),
+            army(1, "Infantry", 64, 10.0),
+        ],
+    };
+    scenario.apply(&mut config).unwrap();
+    config.validate().unwrap();
+    scenario.validate(&config).unwrap();
+    let units = scenario.spawn(&config, &mut StdRng::seed_from_u64(config.seed()));
+    let simulation = CpuSimulation::new(&config, &units, UniformData::new(&config));
+    (config, simulation)
+}
+
+fn step(simulation: &mut CpuSimulation, ticks: std::ops::RangeInclusive<u64>) {
+    for tick in ticks {
+        simulation.step(tick.is_multiple_of(COMPACTION_INTERVAL));
+    }
+}
+
+fn save(config: &SimulationConfig, simulation: &CpuSimulation) -> Snapshot {
+    let projectiles = ProjectileReadback {
+        tick: SAVED_TICK,
+        counter: simulation.projectile_counter(),
+        projectiles: simulation.projectiles().to_vec(),
+    };
+    Snapshot::new(
+        config,
+        SAVED_TICK,
+        simulation.units().to_vec(),
+        &simulation.faction_counts(),
+        &projectiles,
+    )
+}
+
+fn saved_battle() -> (SimulationConfig, CpuSimulation, Vec<u8>) {
+    let (config, mut simulation) = battle();
+    step(&mut simulation, 1..=SAVED_TICK);
+    let snapshot = save(&config, &simulation);
+    assert!(
+        snapshot.header.projectile_counter > 0,
+        "nothing was fired before the snapshot, the projectiles are not covered"
+    );
+    let bytes = snapshot.to_bytes();
+    (config, simulation, bytes)
+}
+
+#[test]
+fn snapshots_round_trip() {
+    let (config, simulation, bytes) = saved_battle();
+    let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+    snapshot.check(&config).unwrap();
+    assert_eq!(snapshot.header, save(&config, &simulation).header);
+    assert_eq!(snapshot.units.len(), simulation.units().len());
+    assert_eq!(snapshot.projectiles.len(), config.max_projectiles as usize);
+    assert_eq!(snapshot.to_bytes(), bytes);
+}
+
+#[test]
+fn resumed_battles_match_uninterrupted_ones() {
+    let (_, mut simulation, bytes) = saved_battle();
+    let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+    let (_, mut resumed) = battle();
+    resumed.restore(
+        &snapshot.units,
+        &snapshot.header.kills,
+        &snapshot.projectiles,
+        snapshot.header.projectile_counter,
+    );
+    step(&mut simulation, SAVED_TICK + 1..=SAVED_TICK * 3);
+    step(&mut resumed, SAVED_TICK + 1..=SAVED_TICK * 3);
+    let state = |simulation: &CpuSimulation| {
+        simulation
+            .units()
+            .iter()
+            .map(|unit| (unit.id, unit.health, unit.current_state))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(state(&resumed), state(&simulation));
+    let kills = |simulation: &CpuSimulation| {
+        simulation
+            .faction_counts()
+            .iter()
+            .map(|counts| (counts.alive, counts.kills))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(kills(&resumed), kills(&simulation));
+    assert_eq!(
+        resumed.projectile_counter(),
+        simulation.projectile_counter()
+    );
+}
+
+#[test]
+fn truncated_snapshots_are_rejected() {
+    let (_, _, bytes) = saved_battle();
+    for length in (0..bytes.len()).step_by(7).chain([bytes.len() - 1]) {
+        assert!(
+            matches!(
+                Snapshot::from_bytes(&bytes[..length]),
+                Err(SnapshotError::Format(_))
+            ),
+            "a snapshot cut to {length} of {} bytes was accepted",
+            bytes.len()
+        );
+    }
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert!(matches!(
+        Snapshot::from_bytes(&longer),
+        Err(SnapshotError::Format(_))
+    ));
+}
+
+#[test]
+fn other_versions_are_rejected() {
+    let (_, _, mut bytes) = saved_battle();
+    //the version follows the 8 byte marker
+    bytes[8..12].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+    match Snapshot::from_bytes(&bytes) {
+        Err(SnapshotError::Format(reason)) => assert!(reason.contains("version"), "{reason}"),
+        _ => panic!("a snapshot of another version was accepted"),
+    }
+    bytes[0] = b'X';
+    assert!(matches!(
+        Snapshot::from_bytes(&bytes),
+        Err(SnapshotError::Format(_))
+    ));
+}
+
+#[test]
+fn snapshots_are_checked_against_the_config() {
+    let (config, _, bytes) = saved_battle();
+    let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+
+    let mut smaller = config.clone();
+    smaller.unit_count = 16;
+    smaller.workgroup_size = 8;
+    assert!(matches!(
+        snapshot.check(&smaller),
+        Err(SnapshotError::TooManyUnits { units, capacity: 16 })
+            if units == snapshot.header.unit_count
+    ));
+
+    let mut projectiles = config.clone();
+    projectiles.max_projectiles *= 2;
+    assert!(matches!(
+        snapshot.check(&projectiles),
+        Err(SnapshotError::Mismatch {
+            field: "max_projectiles",
+            ..
+        })
+    ));
+}